@@ -0,0 +1,366 @@
+use std::fmt;
+use std::path::Path;
+use std::{fs, io};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Ref, ResponseBuilder};
+use utoipa::Modify;
+
+use crate::controller::{ErrorResponse, RobotId};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Path prefixes served by Swagger UI and the OpenAPI document.
+const DOCS_PATHS: [&str; 2] = ["/swagger-ui", "/api-docs"];
+
+/// Probes used by orchestrators, always served without credentials.
+const PROBE_PATHS: [&str; 2] = ["/healthz", "/readyz"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Read,
+    Move,
+    Reset,
+    Reposition,
+    /// Pause, resume, step or speed up the simulation clock.
+    ControlClock,
+    /// Change the world map.
+    EditWorld,
+    /// Store or delete Rhai scripts.
+    EditScripts,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Read => "read_robot",
+            Permission::Move => "move_robot",
+            Permission::Reset => "reset_robot",
+            Permission::Reposition => "reposition_robot",
+            Permission::ControlClock => "control_clock",
+            Permission::EditWorld => "edit_world",
+            Permission::EditScripts => "edit_scripts",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn grants(self, permission: Permission) -> bool {
+        match self {
+            Role::Viewer => permission == Permission::Read,
+            Role::Operator => matches!(permission, Permission::Read | Permission::Move),
+            Role::Admin => true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Credential {
+    pub name: String,
+    /// Accepted as `X-API-Key: <secret>` or `Authorization: Bearer <secret>`.
+    pub secret: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Robots an operator may drive.
+    #[serde(default)]
+    pub robots: Vec<RobotId>,
+}
+
+#[derive(Deserialize)]
+pub struct AuthConfig {
+    /// Serve Swagger UI and the OpenAPI document without credentials.
+    #[serde(default = "default_public_docs")]
+    pub public_docs: bool,
+    pub credentials: Vec<Credential>,
+}
+
+fn default_public_docs() -> bool {
+    true
+}
+
+impl AuthConfig {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let config = fs::read_to_string(path)?;
+        serde_json::from_str(&config).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Load the file named by `ROBOT_AUTH_FILE`; authentication is off when it is unset.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match std::env::var_os("ROBOT_AUTH_FILE") {
+            Some(path) => Self::load(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn find(&self, secret: &str) -> Option<&Credential> {
+        self.credentials
+            .iter()
+            .find(|credential| constant_time_eq(credential.secret.as_bytes(), secret.as_bytes()))
+    }
+}
+
+/// The credential a request was authenticated with, stored in the request extensions.
+#[derive(Clone)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+    pub robots: Vec<RobotId>,
+}
+
+impl Principal {
+    fn check(&self, permission: Permission, robot: RobotId) -> Result<(), String> {
+        if !self.role.grants(permission) {
+            return Err(format!("missing permission {permission}"));
+        }
+        if self.role == Role::Operator
+            && permission == Permission::Move
+            && !self.robots.contains(&robot)
+        {
+            return Err(format!("robot {robot} is not assigned to {}", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Answer `403` when the caller may not perform `permission` on `robot`.
+///
+/// Requests pass when authentication is disabled and no principal is attached.
+pub fn authorize(
+    req: &HttpRequest,
+    permission: Permission,
+    robot: RobotId,
+) -> Option<HttpResponse> {
+    let principal = req.extensions().get::<Principal>().cloned()?;
+    principal
+        .check(permission, robot)
+        .err()
+        .map(|error| HttpResponse::Forbidden().json(ErrorResponse::new(error)))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn presented_secret(req: &ServiceRequest) -> Result<Option<String>, &'static str> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        return key
+            .to_str()
+            .map(|key| Some(key.to_owned()))
+            .map_err(|_| "malformed X-API-Key header");
+    }
+    let Some(authorization) = req.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    authorization
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim().to_owned()))
+        .ok_or("Authorization header must use the Bearer scheme")
+}
+
+fn unauthorized(error: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer")))
+        .json(ErrorResponse::new(error))
+}
+
+/// Reject requests without a known API key or bearer token.
+///
+/// Missing or malformed credentials get `401`, credentials that match nothing get `403`.
+/// Accepted requests carry a [`Principal`] for [`authorize`].
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(config) = req.app_data::<web::Data<AuthConfig>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if PROBE_PATHS.contains(&req.path()) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    if config.public_docs && DOCS_PATHS.iter().any(|docs| req.path().starts_with(docs)) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let secret = match presented_secret(&req) {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return Ok(req.into_response(unauthorized("missing API key or bearer token"))),
+        Err(error) => return Ok(req.into_response(unauthorized(error))),
+    };
+    let Some(credential) = config.find(&secret) else {
+        return Ok(req.into_response(
+            HttpResponse::Forbidden().json(ErrorResponse::new("unknown API key or bearer token")),
+        ));
+    };
+
+    req.extensions_mut().insert(Principal {
+        name: credential.name.clone(),
+        role: credential.role,
+        robots: credential.robots.clone(),
+    });
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// Declares the API key and bearer schemes and the `401`/`403` replies on every operation.
+pub struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+
+        for path in openapi.paths.paths.values_mut() {
+            for operation in [
+                &mut path.get,
+                &mut path.post,
+                &mut path.put,
+                &mut path.delete,
+                &mut path.patch,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                for (status, description) in [
+                    ("401", "Missing or malformed credentials"),
+                    ("403", "Credentials not accepted or missing a permission"),
+                ] {
+                    operation.responses.responses.insert(
+                        status.to_string(),
+                        ResponseBuilder::new()
+                            .description(description)
+                            .content(
+                                "application/json",
+                                utoipa::openapi::ContentBuilder::new()
+                                    .schema(Some(Ref::from_schema_name("ErrorResponse")))
+                                    .build(),
+                            )
+                            .into(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::auth::{authenticate, AuthConfig, Credential, Role};
+    use crate::controller::{move_robot, reset_robot, robot_position, RobotState};
+
+    #[actix_web::test]
+    async fn test_authenticate() {
+        let config = AuthConfig {
+            public_docs: true,
+            credentials: vec![Credential {
+                name: "ci".to_string(),
+                secret: "s3cret".to_string(),
+                role: Role::Viewer,
+                robots: Vec::new(),
+            }],
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(authenticate))
+                .route("/robot_position", web::get().to(robot_position)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/robot_position").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+
+        let req = TestRequest::get()
+            .uri("/robot_position")
+            .insert_header(("authorization", "Bearer wrong"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 403);
+
+        let req = TestRequest::get()
+            .uri("/robot_position")
+            .insert_header(("x-api-key", "s3cret"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let req = TestRequest::get()
+            .uri("/robot_position")
+            .insert_header(("authorization", "Bearer s3cret"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_roles() {
+        let credential = |name: &str, role, robots| Credential {
+            name: name.to_string(),
+            secret: name.to_string(),
+            role,
+            robots,
+        };
+        let config = AuthConfig {
+            public_docs: true,
+            credentials: vec![
+                credential("viewer", Role::Viewer, vec![]),
+                credential("operator", Role::Operator, vec![0]),
+                credential("admin", Role::Admin, vec![]),
+            ],
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(authenticate))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reset_robot", web::post().to(reset_robot)),
+        )
+        .await;
+        let status = |uri: &str, key: &str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header(("x-api-key", key))
+                .set_json(serde_json::json!({ "instructions": "A" }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, status("/move_robot", "viewer")).await;
+        assert_eq!(resp.status(), 403);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["error"], "missing permission move_robot");
+
+        let resp = call_service(&app, status("/move_robot", "operator")).await;
+        assert_eq!(resp.status(), 200);
+        let resp = call_service(&app, status("/move_robot?robot_id=1", "operator")).await;
+        assert_eq!(resp.status(), 403);
+        let resp = call_service(&app, status("/reset_robot", "operator")).await;
+        assert_eq!(resp.status(), 403);
+        let resp = call_service(&app, status("/reset_robot", "admin")).await;
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::controller::{RobotId, RobotModel};
+use crate::pose::Pose;
+use crate::simulation::Clock;
+use crate::world::{Cell, World};
+
+#[derive(Clone)]
+pub struct BatteryConfig {
+    /// Energy of a full battery.
+    pub capacity: f64,
+    /// Energy one `A` uses.
+    pub advance_cost: f64,
+    /// Energy one `L` or `R` uses.
+    pub turn_cost: f64,
+    /// Energy a charging station adds per second.
+    pub charge_rate: f64,
+}
+
+impl BatteryConfig {
+    /// Enabled by `ROBOT_BATTERY_CAPACITY`, with costs from `ROBOT_BATTERY_ADVANCE_COST`
+    /// (default 1) and `ROBOT_BATTERY_TURN_COST` (default 0.5), and the charging rate per
+    /// second from `ROBOT_BATTERY_CHARGE_RATE` (default 10).
+    pub fn from_env() -> Option<Self> {
+        let energy = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| value.is_finite() && *value >= 0.0)
+        };
+        Some(BatteryConfig {
+            capacity: energy("ROBOT_BATTERY_CAPACITY").filter(|capacity| *capacity > 0.0)?,
+            advance_cost: energy("ROBOT_BATTERY_ADVANCE_COST").unwrap_or(1.0),
+            turn_cost: energy("ROBOT_BATTERY_TURN_COST").unwrap_or(0.5),
+            charge_rate: energy("ROBOT_BATTERY_CHARGE_RATE").unwrap_or(10.0),
+        })
+    }
+
+    fn cost(&self, instruction: char) -> f64 {
+        match instruction {
+            'A' => self.advance_cost,
+            _ => self.turn_cost,
+        }
+    }
+}
+
+struct Charge {
+    level: f64,
+    /// Clock time the level was last brought up to date.
+    updated: Duration,
+}
+
+/// How much of a program the battery can power.
+pub struct Plan {
+    /// Instructions that can run, from the start of the program.
+    pub steps: usize,
+    /// Level left once they have.
+    pub level: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatteryDepleted {
+    pub error: String,
+    /// Instructions carried out before the battery ran out.
+    pub steps: usize,
+    pub pose: RobotModel,
+    pub battery: f64,
+}
+
+/// Keep levels readable; charging over time would otherwise leave long fractions.
+fn rounded(level: f64) -> f64 {
+    (level * 100.0).round() / 100.0
+}
+
+/// Per-robot battery levels. Robots start full.
+pub struct Batteries {
+    config: BatteryConfig,
+    world: web::Data<World>,
+    clock: Arc<dyn Clock>,
+    charges: Mutex<HashMap<RobotId, Charge>>,
+}
+
+impl Batteries {
+    pub fn new(config: BatteryConfig, world: web::Data<World>, clock: Arc<dyn Clock>) -> Self {
+        Batteries {
+            config,
+            world,
+            clock,
+            charges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bring the level up to date, charging for the time the robot stood on a station.
+    ///
+    /// Robots only move through the server, so `pose` is where it has been since the last update.
+    fn settle<'a>(
+        &self,
+        charges: &'a mut HashMap<RobotId, Charge>,
+        robot_id: RobotId,
+        pose: Pose,
+    ) -> &'a mut Charge {
+        let now = self.clock.now();
+        let charge = charges.entry(robot_id).or_insert(Charge {
+            level: self.config.capacity,
+            updated: now,
+        });
+        let cell = Cell {
+            x: pose.x,
+            y: pose.y,
+        };
+        if self.world.map().charging_stations.contains(&cell) {
+            let charged = self.config.charge_rate * (now - charge.updated).as_secs_f64();
+            charge.level = (charge.level + charged).min(self.config.capacity);
+        }
+        charge.updated = now;
+        charge
+    }
+
+    /// The robot's battery level, standing at `pose`.
+    pub fn level(&self, robot_id: RobotId, pose: Pose) -> f64 {
+        let mut charges = self.charges.lock().unwrap();
+        rounded(self.settle(&mut charges, robot_id, pose).level)
+    }
+
+    /// Work out how many instructions of `program` the battery powers once the `queued` ones
+    /// ahead of it have run. Nothing is used until [`Self::commit`].
+    pub fn plan(&self, robot_id: RobotId, pose: Pose, queued: &[char], program: &[char]) -> Plan {
+        let mut charges = self.charges.lock().unwrap();
+        let mut level = self.settle(&mut charges, robot_id, pose).level;
+        let mut steps = 0;
+        for (position, &instruction) in queued.iter().chain(program).enumerate() {
+            let cost = self.config.cost(instruction);
+            if cost > level {
+                break;
+            }
+            level -= cost;
+            if position >= queued.len() {
+                steps += 1;
+            }
+        }
+        Plan {
+            steps,
+            level: rounded(level),
+        }
+    }
+
+    /// Use the energy of a plan whose instructions were carried out.
+    pub fn commit(&self, robot_id: RobotId, plan: &Plan) {
+        let now = self.clock.now();
+        self.charges.lock().unwrap().insert(
+            robot_id,
+            Charge {
+                level: plan.level,
+                updated: now,
+            },
+        );
+    }
+
+    /// Energy left once the `queued` instructions have run, unrounded so it can be spent
+    /// instruction by instruction with [`Self::cost`].
+    pub fn energy(&self, robot_id: RobotId, pose: Pose, queued: &[char]) -> f64 {
+        let mut charges = self.charges.lock().unwrap();
+        let level = self.settle(&mut charges, robot_id, pose).level;
+        queued.iter().fold(level, |level, &instruction| {
+            level - self.config.cost(instruction)
+        })
+    }
+
+    pub fn cost(&self, instruction: char) -> f64 {
+        self.config.cost(instruction)
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.config.capacity
+    }
+
+    /// Fill the robot's battery, e.g. because it was reset.
+    pub fn refill(&self, robot_id: RobotId) {
+        self.charges.lock().unwrap().remove(&robot_id);
+    }
+}
+
+/// The robot's battery level when batteries are enabled.
+pub fn battery_level(req: &HttpRequest, robot_id: RobotId, robot: &RobotModel) -> Option<f64> {
+    let batteries = req.app_data::<web::Data<Batteries>>()?;
+    Some(batteries.level(robot_id, Pose::from(robot)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::battery::{Batteries, BatteryConfig};
+    use crate::controller::{move_robot, robot_position, RobotState};
+    use crate::events::EventLog;
+    use crate::history::history;
+    use crate::simulation::ManualClock;
+    use crate::storage::TempStorage;
+    use crate::world::{Cell, World, WorldMap};
+
+    #[actix_web::test]
+    async fn test_battery_runs_out_and_recharges() {
+        let storage = TempStorage::new("battery");
+        storage
+            .write_json(
+                crate::world::WORLD_FILE,
+                &WorldMap {
+                    charging_stations: [Cell { x: 0, y: 3 }].into(),
+                    ..WorldMap::default()
+                },
+            )
+            .unwrap();
+        let world = web::Data::new(World::load(storage.clone()).unwrap());
+        let clock = Arc::new(ManualClock::default());
+        let config = BatteryConfig {
+            capacity: 4.0,
+            advance_cost: 1.0,
+            turn_cost: 0.5,
+            charge_rate: 1.0,
+        };
+        let batteries = web::Data::new(Batteries::new(config, world, clock.clone()));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(EventLog::open(&storage).unwrap()))
+                .app_data(batteries)
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/robot_position", web::get().to(robot_position))
+                .route("/robots/{id}/history", web::get().to(history)),
+        )
+        .await;
+        let program = |instructions: &str| {
+            TestRequest::post()
+                .uri("/move_robot")
+                .set_json(serde_json::json!({ "instructions": instructions }))
+                .to_request()
+        };
+
+        let pose: serde_json::Value = call_and_read_body_json(&app, program("AAA")).await;
+        assert_eq!(pose["y"], 3);
+        assert_eq!(pose["battery"], 1.0);
+
+        let resp = call_service(&app, program("RAA")).await;
+        assert_eq!(resp.status(), 409);
+        let depleted: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(depleted["steps"], 1);
+        assert_eq!(depleted["battery"], 0.5);
+
+        // Turning on the station does not move the robot off it, so it charges.
+        clock.advance(Duration::from_secs(2));
+        let req = TestRequest::get().uri("/robot_position").to_request();
+        let pose: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(pose["battery"], 2.5);
+
+        let req = TestRequest::get().uri("/robots/0/history").to_request();
+        let trace: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(trace[0]["battery"], 1.0);
+        assert_eq!(trace[1]["instructions"], "R");
+        assert_eq!(trace[1]["battery"], 0.5);
+    }
+}
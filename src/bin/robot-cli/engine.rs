@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use robot::pose::{Heading, Pose};
+use robot::solutions::{command_pattern, no_pattern, state_pattern, type_state_pattern};
+
+/// Which of the four solutions drives the robot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum EngineKind {
+    #[default]
+    NoPattern,
+    TypeState,
+    Command,
+    State,
+}
+
+/// A robot driven by one of the solutions.
+pub trait Engine {
+    /// Carry out `L`, `R` or `A`.
+    fn execute(&mut self, instruction: char);
+
+    fn pose(&self) -> Pose;
+}
+
+impl EngineKind {
+    /// A robot of this kind standing at `pose`.
+    pub fn start(self, pose: Pose) -> Box<dyn Engine> {
+        let Pose { x, y, facing } = pose;
+        match self {
+            EngineKind::NoPattern => Box::new(no_pattern::Robot::new(
+                x,
+                y,
+                match facing {
+                    Heading::North => no_pattern::Direction::North,
+                    Heading::East => no_pattern::Direction::East,
+                    Heading::South => no_pattern::Direction::South,
+                    Heading::West => no_pattern::Direction::West,
+                },
+            )),
+            EngineKind::TypeState => Box::new(match facing {
+                Heading::North => {
+                    type_state_pattern::RobotWithFace::North(type_state_pattern::Robot::new(x, y))
+                }
+                Heading::East => {
+                    type_state_pattern::RobotWithFace::East(type_state_pattern::Robot::new(x, y))
+                }
+                Heading::South => {
+                    type_state_pattern::RobotWithFace::South(type_state_pattern::Robot::new(x, y))
+                }
+                Heading::West => {
+                    type_state_pattern::RobotWithFace::West(type_state_pattern::Robot::new(x, y))
+                }
+            }),
+            EngineKind::Command => Box::new(command_pattern::RobotController::new(
+                x,
+                y,
+                match facing {
+                    Heading::North => command_pattern::Direction::North,
+                    Heading::East => command_pattern::Direction::East,
+                    Heading::South => command_pattern::Direction::South,
+                    Heading::West => command_pattern::Direction::West,
+                },
+            )),
+            EngineKind::State => Box::new(state_pattern::Robot::new(
+                x,
+                y,
+                match facing {
+                    Heading::North => Arc::new(state_pattern::North),
+                    Heading::East => Arc::new(state_pattern::East),
+                    Heading::South => Arc::new(state_pattern::South),
+                    Heading::West => Arc::new(state_pattern::West),
+                },
+            )),
+        }
+    }
+}
+
+impl Engine for no_pattern::Robot {
+    fn execute(&mut self, instruction: char) {
+        no_pattern::Robot::execute(self, instruction);
+    }
+
+    fn pose(&self) -> Pose {
+        let facing = match self.facing {
+            no_pattern::Direction::North => Heading::North,
+            no_pattern::Direction::East => Heading::East,
+            no_pattern::Direction::South => Heading::South,
+            no_pattern::Direction::West => Heading::West,
+        };
+        Pose {
+            x: self.x,
+            y: self.y,
+            facing,
+        }
+    }
+}
+
+impl Engine for type_state_pattern::RobotWithFace {
+    fn execute(&mut self, instruction: char) {
+        type_state_pattern::RobotWithFace::execute(self, instruction);
+    }
+
+    fn pose(&self) -> Pose {
+        let (position, facing) = match self {
+            type_state_pattern::RobotWithFace::North(robot) => (&robot.position, Heading::North),
+            type_state_pattern::RobotWithFace::East(robot) => (&robot.position, Heading::East),
+            type_state_pattern::RobotWithFace::South(robot) => (&robot.position, Heading::South),
+            type_state_pattern::RobotWithFace::West(robot) => (&robot.position, Heading::West),
+        };
+        Pose {
+            x: position.x,
+            y: position.y,
+            facing,
+        }
+    }
+}
+
+impl Engine for command_pattern::RobotController {
+    fn execute(&mut self, instruction: char) {
+        self.process_instruction(instruction);
+    }
+
+    fn pose(&self) -> Pose {
+        let (x, y, direction) = self.position();
+        let facing = match direction {
+            command_pattern::Direction::North => Heading::North,
+            command_pattern::Direction::East => Heading::East,
+            command_pattern::Direction::South => Heading::South,
+            command_pattern::Direction::West => Heading::West,
+        };
+        Pose { x, y, facing }
+    }
+}
+
+impl Engine for state_pattern::Robot {
+    fn execute(&mut self, instruction: char) {
+        state_pattern::Robot::execute(self, instruction);
+    }
+
+    fn pose(&self) -> Pose {
+        let facing = match self.facing.name() {
+            "NORTH" => Heading::North,
+            "EAST" => Heading::East,
+            "SOUTH" => Heading::South,
+            _ => Heading::West,
+        };
+        Pose {
+            x: self.x,
+            y: self.y,
+            facing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::ValueEnum;
+    use robot::pose::{Heading, Pose};
+
+    use crate::engine::EngineKind;
+
+    #[test]
+    fn test_engines_agree() {
+        for kind in EngineKind::value_variants() {
+            let mut robot = kind.start(Pose {
+                x: 7,
+                y: 3,
+                facing: Heading::North,
+            });
+            "RAALAL"
+                .chars()
+                .for_each(|instruction| robot.execute(instruction));
+            assert_eq!(
+                robot.pose(),
+                Pose {
+                    x: 9,
+                    y: 4,
+                    facing: Heading::West
+                },
+                "{kind:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Run robot programs from the terminal without starting the server.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use robot::controller::parse_program;
+use robot::pose::{Heading, Pose};
+use robot::world::{Blocked, WorldMap};
+
+use crate::engine::{Engine, EngineKind};
+use crate::repl::{Local, Remote, Session};
+
+mod engine;
+mod repl;
+
+/// The program is not made of `L`, `R` and `A`.
+const EXIT_INVALID_PROGRAM: u8 = 3;
+/// The robot would have driven into an obstacle or off the arena.
+const EXIT_COLLISION: u8 = 4;
+
+#[derive(Parser)]
+#[command(
+    name = "robot-cli",
+    version,
+    about = "Drive a simulated robot from the terminal"
+)]
+#[command(
+    after_help = "Exit codes: 0 done, 1 could not read the input, 2 bad arguments, \
+                        3 invalid program, 4 collision"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a program and print where the robot ends up.
+    Run(RunArgs),
+    /// Drive a robot line by line, locally or on a running server.
+    Repl(ReplArgs),
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Start pose as `x,y,heading`, e.g. `7,3,N`.
+    #[arg(long, default_value = "0,0,N", value_parser = parse_pose)]
+    start: Pose,
+    /// Solution that drives the robot.
+    #[arg(long, value_enum, default_value_t)]
+    engine: EngineKind,
+    /// Print the pose after every instruction.
+    #[arg(long)]
+    trace: bool,
+    /// World map with bounds and obstacles, as served by `GET /world`.
+    #[arg(long)]
+    world: Option<PathBuf>,
+    /// Read the program from this file.
+    #[arg(long, short, conflicts_with = "program")]
+    file: Option<PathBuf>,
+    /// Instructions such as `RAALAL`. Read from stdin when absent or `-`.
+    program: Option<String>,
+}
+
+#[derive(Args)]
+struct ReplArgs {
+    /// Start pose as `x,y,heading`, also where `reset` goes back to.
+    #[arg(long, default_value = "0,0,N", value_parser = parse_pose)]
+    start: Pose,
+    /// Solution that drives the robot.
+    #[arg(long, value_enum, default_value_t)]
+    engine: EngineKind,
+    /// World map with bounds and obstacles, as served by `GET /world`.
+    #[arg(long)]
+    world: Option<PathBuf>,
+    /// Drive a robot on this server instead, e.g. `http://127.0.0.1:8080`.
+    #[arg(long, conflicts_with_all = ["start", "engine", "world"])]
+    server: Option<String>,
+    /// Robot to drive on the server.
+    #[arg(long, default_value_t = 0, requires = "server")]
+    robot_id: u32,
+    /// Credential sent to the server as `X-API-Key`.
+    #[arg(long, requires = "server")]
+    api_key: Option<String>,
+}
+
+/// Parse `x,y,heading` with the heading as `N`, `E`, `S` or `W`.
+fn parse_pose(text: &str) -> Result<Pose, String> {
+    let invalid = || format!("expected x,y,heading such as 7,3,N, got '{text}'");
+    let [x, y, heading] = text.split(',').map(str::trim).collect::<Vec<_>>()[..] else {
+        return Err(invalid());
+    };
+    let facing = match heading.to_ascii_uppercase().as_str() {
+        "N" | "NORTH" => Heading::North,
+        "E" | "EAST" => Heading::East,
+        "S" | "SOUTH" => Heading::South,
+        "W" | "WEST" => Heading::West,
+        _ => return Err(invalid()),
+    };
+    Ok(Pose {
+        x: x.parse().map_err(|_| invalid())?,
+        y: y.parse().map_err(|_| invalid())?,
+        facing,
+    })
+}
+
+fn format_pose(pose: Pose) -> String {
+    let heading = match pose.facing {
+        Heading::North => 'N',
+        Heading::East => 'E',
+        Heading::South => 'S',
+        Heading::West => 'W',
+    };
+    format!("{},{},{heading}", pose.x, pose.y)
+}
+
+/// The robot stopped in front of something it cannot drive into.
+#[derive(Debug, PartialEq)]
+struct Collision {
+    pose: Pose,
+    blocked: Blocked,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.blocked.fmt(f)
+    }
+}
+
+/// Drive the robot through `program`, stopping before an `A` into anything but a free cell.
+fn run_program(
+    robot: &mut dyn Engine,
+    map: &WorldMap,
+    program: &[char],
+    mut trace: impl FnMut(char, Pose),
+) -> Result<Pose, Collision> {
+    let blocked = map.collision(robot.pose(), program);
+    let runnable = blocked.map_or(program.len(), |blocked| blocked.steps);
+    for &instruction in &program[..runnable] {
+        robot.execute(instruction);
+        trace(instruction, robot.pose());
+    }
+    match blocked {
+        Some(blocked) => Err(Collision {
+            pose: robot.pose(),
+            blocked,
+        }),
+        None => Ok(robot.pose()),
+    }
+}
+
+/// The world map at `path`, or an empty unbounded one.
+fn read_world(path: Option<&PathBuf>) -> Result<WorldMap, String> {
+    let Some(path) = path else {
+        return Ok(WorldMap::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("could not read {}: {err}", path.display()))?;
+    serde_json::from_str(&text)
+        .map_err(|err| format!("invalid world map {}: {err}", path.display()))
+}
+
+fn read_program(args: &RunArgs) -> Result<String, String> {
+    match (&args.file, args.program.as_deref()) {
+        (Some(path), _) => std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {err}", path.display())),
+        (None, Some(program)) if program != "-" => Ok(program.to_string()),
+        _ => {
+            let mut program = String::new();
+            io::stdin()
+                .read_to_string(&mut program)
+                .map_err(|err| format!("could not read stdin: {err}"))?;
+            Ok(program)
+        }
+    }
+}
+
+fn run(args: RunArgs) -> ExitCode {
+    let (program, map) = match read_program(&args)
+        .and_then(|program| Ok((program, read_world(args.world.as_ref())?)))
+    {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("robot-cli: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let program = match parse_program(&program) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("robot-cli: {err}");
+            return ExitCode::from(EXIT_INVALID_PROGRAM);
+        }
+    };
+
+    let mut robot = args.engine.start(args.start);
+    if args.trace {
+        println!("  {}", format_pose(args.start));
+    }
+    let trace = |instruction, pose| {
+        if args.trace {
+            println!("{instruction} {}", format_pose(pose));
+        }
+    };
+    match run_program(robot.as_mut(), &map, &program, trace) {
+        Ok(pose) => {
+            println!("{}", format_pose(pose));
+            ExitCode::SUCCESS
+        }
+        Err(collision) => {
+            println!("{}", format_pose(collision.pose));
+            eprintln!("robot-cli: {collision}");
+            ExitCode::from(EXIT_COLLISION)
+        }
+    }
+}
+
+fn repl(args: ReplArgs) -> ExitCode {
+    let result = match args.server {
+        Some(server) => repl::run(Session::new(Remote::new(
+            &server,
+            args.robot_id,
+            args.api_key,
+        ))),
+        None => read_world(args.world.as_ref())
+            .and_then(|map| repl::run(Session::new(Local::new(args.engine, args.start, map)))),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("robot-cli: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::Repl(args) => repl(args),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use robot::pose::{Heading, Pose};
+    use robot::world::{Blocked, Cell, Reading, WorldMap};
+
+    use crate::engine::EngineKind;
+    use crate::{format_pose, parse_pose, run_program, Collision};
+
+    #[test]
+    fn test_run_stops_at_obstacles() {
+        let start = parse_pose("7,3,N").unwrap();
+        assert!(parse_pose("7,3").is_err());
+        assert!(parse_pose("7,3,Q").is_err());
+
+        let mut robot = EngineKind::Command.start(start);
+        let mut trace = Vec::new();
+        let program: Vec<char> = "RAALAL".chars().collect();
+        let pose = run_program(robot.as_mut(), &WorldMap::default(), &program, |_, pose| {
+            trace.push(format_pose(pose))
+        });
+        assert_eq!(pose.map(format_pose), Ok("9,4,W".to_string()));
+        assert_eq!(trace[..3], ["7,3,E", "8,3,E", "9,3,E"]);
+
+        let map = WorldMap {
+            obstacles: [Cell { x: 9, y: 3 }].into(),
+            ..WorldMap::default()
+        };
+        let mut robot = EngineKind::State.start(start);
+        let collision = run_program(robot.as_mut(), &map, &program, |_, _| ()).unwrap_err();
+        assert_eq!(
+            collision,
+            Collision {
+                pose: Pose {
+                    x: 8,
+                    y: 3,
+                    facing: Heading::East
+                },
+                blocked: Blocked {
+                    steps: 2,
+                    cell: Cell { x: 9, y: 3 },
+                    reading: Reading::Obstacle,
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashSet;
+
+use robot::controller::InvalidInstruction;
+use robot::pose::Pose;
+use robot::render::{ascii, Viewport};
+use robot::world::{Cell, WorldMap};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::json;
+
+use crate::engine::{Engine, EngineKind};
+use crate::{format_pose, parse_pose, run_program};
+
+/// Instructions one line may repeat up to, e.g. `10000A`.
+const MAX_LINE_INSTRUCTIONS: usize = 10_000;
+/// Cells shown around the robot after every step.
+const VIEW_WIDTH: u32 = 21;
+const VIEW_HEIGHT: u32 = 11;
+
+const COMMANDS: [&str; 6] = ["pos", "undo", "reset", "place", "help", "quit"];
+
+const HELP: &str = "\
+L, R, A     turn left, turn right, advance; combine and repeat as in 3A or RAAL
+pos         print the pose
+undo        go back to the pose before the last command
+reset       go back to the start pose
+place X Y H put the robot at X,Y facing N, E, S or W
+quit        leave, as does Ctrl-D";
+
+/// One line typed at the prompt.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Drive(Vec<char>),
+    Pos,
+    Undo,
+    Reset,
+    Place(Pose),
+    Help,
+    Quit,
+}
+
+/// Parse a command, or instructions where a count repeats the one after it.
+pub fn parse_line(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        None => return Ok(None),
+        Some("pos") => Command::Pos,
+        Some("undo") => Command::Undo,
+        Some("reset") => Command::Reset,
+        Some("help") => Command::Help,
+        Some("quit" | "exit") => Command::Quit,
+        Some("place") => Command::Place(parse_pose(&words.collect::<Vec<_>>().join(","))?),
+        Some(_) => return parse_instructions(line).map(|program| Some(Command::Drive(program))),
+    };
+    Ok(Some(command))
+}
+
+fn parse_instructions(line: &str) -> Result<Vec<char>, String> {
+    let mut program = Vec::new();
+    let mut count: Option<usize> = None;
+    for (position, instruction) in line.chars().enumerate() {
+        match instruction.to_ascii_uppercase() {
+            digit @ '0'..='9' => {
+                let digit = digit.to_digit(10).unwrap_or_default() as usize;
+                count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            }
+            instruction @ ('L' | 'R' | 'A') => {
+                let times = count.take().unwrap_or(1);
+                if program.len() + times > MAX_LINE_INSTRUCTIONS {
+                    return Err(format!(
+                        "at most {MAX_LINE_INSTRUCTIONS} instructions per line"
+                    ));
+                }
+                program.extend(std::iter::repeat_n(instruction, times));
+            }
+            _ if instruction.is_whitespace() && count.is_none() => {}
+            _ => {
+                let err = InvalidInstruction {
+                    position,
+                    instruction,
+                };
+                return Err(err.to_string());
+            }
+        }
+    }
+    match count {
+        Some(_) => Err("a count needs an instruction after it".to_string()),
+        None => Ok(program),
+    }
+}
+
+/// Where the robot the REPL drives lives.
+pub trait Backend {
+    fn pose(&mut self) -> Result<Pose, String>;
+
+    /// Carry out `program`. An error means it stopped early, possibly after some of it ran.
+    fn drive(&mut self, program: &[char]) -> Result<(), String>;
+
+    fn place(&mut self, pose: Pose) -> Result<(), String>;
+
+    fn reset(&mut self) -> Result<(), String>;
+
+    fn map(&mut self) -> Result<WorldMap, String>;
+}
+
+/// A robot simulated in this process.
+pub struct Local {
+    kind: EngineKind,
+    start: Pose,
+    robot: Box<dyn Engine>,
+    map: WorldMap,
+}
+
+impl Local {
+    pub fn new(kind: EngineKind, start: Pose, map: WorldMap) -> Self {
+        Local {
+            kind,
+            start,
+            robot: kind.start(start),
+            map,
+        }
+    }
+}
+
+impl Backend for Local {
+    fn pose(&mut self) -> Result<Pose, String> {
+        Ok(self.robot.pose())
+    }
+
+    fn drive(&mut self, program: &[char]) -> Result<(), String> {
+        run_program(self.robot.as_mut(), &self.map, program, |_, _| ())
+            .map(|_| ())
+            .map_err(|collision| collision.to_string())
+    }
+
+    fn place(&mut self, pose: Pose) -> Result<(), String> {
+        self.robot = self.kind.start(pose);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.place(self.start)
+    }
+
+    fn map(&mut self) -> Result<WorldMap, String> {
+        Ok(self.map.clone())
+    }
+}
+
+/// A robot on a running server, driven through its REST API.
+pub struct Remote {
+    agent: ureq::Agent,
+    server: String,
+    robot_id: u32,
+    api_key: Option<String>,
+}
+
+impl Remote {
+    pub fn new(server: &str, robot_id: u32, api_key: Option<String>) -> Self {
+        Remote {
+            agent: ureq::Agent::new(),
+            server: server.trim_end_matches('/').to_string(),
+            robot_id,
+            api_key,
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{path}", self.server));
+        match &self.api_key {
+            Some(api_key) => request.set("X-API-Key", api_key),
+            None => request,
+        }
+    }
+
+    fn call(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, String> {
+        let request = self.request(method, path);
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+        match response {
+            Ok(response) => response.into_json().map_err(|err| err.to_string()),
+            Err(ureq::Error::Status(status, response)) => {
+                let body: serde_json::Value = response.into_json().unwrap_or_default();
+                Err(match body["error"].as_str() {
+                    Some(error) => format!("server answered {status}: {error}"),
+                    None => format!("server answered {status}"),
+                })
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+impl Backend for Remote {
+    fn pose(&mut self) -> Result<Pose, String> {
+        // Sensors report the pose the same way whichever engine the server runs.
+        let sensors = self.call("GET", &format!("/robots/{}/sensors", self.robot_id), None)?;
+        serde_json::from_value(sensors["pose"].clone()).map_err(|err| err.to_string())
+    }
+
+    fn drive(&mut self, program: &[char]) -> Result<(), String> {
+        let instructions: String = program.iter().collect();
+        let path = format!("/move_robot?robot_id={}", self.robot_id);
+        self.call("POST", &path, Some(json!({ "instructions": instructions })))
+            .map(|_| ())
+    }
+
+    fn place(&mut self, pose: Pose) -> Result<(), String> {
+        let path = format!("/reposition_robot?robot_id={}", self.robot_id);
+        self.call("POST", &path, Some(json!(pose))).map(|_| ())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        let path = format!("/reset_robot?robot_id={}", self.robot_id);
+        self.call("POST", &path, None).map(|_| ())
+    }
+
+    fn map(&mut self) -> Result<WorldMap, String> {
+        let map = self.call("GET", "/world", None)?;
+        serde_json::from_value(map).map_err(|err| err.to_string())
+    }
+}
+
+/// The robot being driven and the poses `undo` returns to.
+pub struct Session<B> {
+    backend: B,
+    undo: Vec<Pose>,
+}
+
+impl<B: Backend> Session<B> {
+    pub fn new(backend: B) -> Self {
+        Session {
+            backend,
+            undo: Vec::new(),
+        }
+    }
+
+    /// Carry out a command and return what to print.
+    pub fn execute(&mut self, command: Command) -> Result<String, String> {
+        let before = self.backend.pose()?;
+        let result = match &command {
+            Command::Pos => return Ok(format_pose(before)),
+            Command::Help => return Ok(HELP.to_string()),
+            Command::Quit => return Ok(String::new()),
+            Command::Undo => match self.undo.pop() {
+                Some(pose) => self.backend.place(pose),
+                None => return Err("nothing to undo".to_string()),
+            },
+            Command::Drive(program) => self.backend.drive(program),
+            Command::Place(pose) => self.backend.place(*pose),
+            Command::Reset => self.backend.reset(),
+        };
+        let after = self.backend.pose()?;
+        if after != before && !matches!(command, Command::Undo) {
+            self.undo.push(before);
+        }
+        let view = self.view(after)?;
+        match result {
+            Ok(()) => Ok(view),
+            Err(err) => Err(format!("{view}{err}")),
+        }
+    }
+
+    /// The grid around the robot and its pose.
+    fn view(&mut self, pose: Pose) -> Result<String, String> {
+        let viewport = Viewport {
+            center: Cell::from(pose),
+            width: VIEW_WIDTH,
+            height: VIEW_HEIGHT,
+        };
+        let map = self.backend.map()?;
+        Ok(format!(
+            "{}{}",
+            ascii(&map, &[pose], &HashSet::new(), &viewport),
+            format_pose(pose)
+        ))
+    }
+}
+
+/// Completes command names at the start of the line.
+struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        if typed.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(typed))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
+
+/// Read commands until `quit` or end of input.
+pub fn run(mut session: Session<impl Backend>) -> Result<(), String> {
+    let mut editor: Editor<CommandCompleter, DefaultHistory> =
+        Editor::new().map_err(|err| err.to_string())?;
+    editor.set_helper(Some(CommandCompleter));
+    let pose = session.backend.pose()?;
+    println!("{}", session.view(pose)?);
+    println!("Type help for the commands.");
+    loop {
+        let line = match editor.readline("robot> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err.to_string()),
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        let output = match parse_line(&line) {
+            Ok(None) => continue,
+            Ok(Some(Command::Quit)) => return Ok(()),
+            Ok(Some(command)) => session.execute(command),
+            Err(err) => Err(err),
+        };
+        match output {
+            Ok(output) => println!("{output}"),
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use robot::pose::{Heading, Pose};
+    use robot::world::{Cell, WorldMap};
+
+    use crate::engine::EngineKind;
+    use crate::repl::{parse_line, Backend, Command, Local, Session};
+
+    #[test]
+    fn test_session() {
+        assert_eq!(
+            parse_line("3A l"),
+            Ok(Some(Command::Drive(vec!['A', 'A', 'A', 'L'])))
+        );
+        assert_eq!(
+            parse_line("place 7 3 N"),
+            Ok(Some(Command::Place(Pose {
+                x: 7,
+                y: 3,
+                facing: Heading::North
+            })))
+        );
+        assert_eq!(parse_line("  "), Ok(None));
+        assert!(parse_line("3").is_err());
+        assert!(parse_line("AXA").is_err());
+
+        let map = WorldMap {
+            obstacles: [Cell { x: 0, y: 3 }].into(),
+            ..WorldMap::default()
+        };
+        let start = Pose {
+            x: 0,
+            y: 0,
+            facing: Heading::North,
+        };
+        let mut session = Session::new(Local::new(EngineKind::TypeState, start, map));
+        let run = |session: &mut Session<Local>, line| {
+            let command = parse_line(line).unwrap().unwrap();
+            session.execute(command)
+        };
+        let view = run(&mut session, "A").unwrap();
+        let row = |robot| format!("{0}{robot}{0}\n", ".".repeat(10));
+        assert!(
+            view.contains(&format!("{}{}{}", row('#'), row('.'), row('^'))),
+            "{view}"
+        );
+        assert!(view.ends_with("0,1,N"));
+        let err = run(&mut session, "5A").unwrap_err();
+        assert!(err.ends_with("collision with obstacle at 0,3 after 1 instructions"));
+        assert_eq!(session.backend.pose().unwrap().y, 2);
+
+        run(&mut session, "undo").unwrap();
+        run(&mut session, "undo").unwrap();
+        assert_eq!(session.backend.pose(), Ok(start));
+        assert!(run(&mut session, "undo").is_err());
+
+        run(&mut session, "place 4 4 W").unwrap();
+        assert_eq!(run(&mut session, "pos"), Ok("4,4,W".to_string()));
+        run(&mut session, "reset").unwrap();
+        assert_eq!(session.backend.pose(), Ok(start));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{authorize, Permission};
+use crate::battery::battery_level;
+use crate::controller::{
+    robot_not_found, ErrorResponse, RobotId, RobotModel, RobotState, VersionedRobot, DEFAULT_ROBOT,
+};
+use crate::events::{record_events, Change};
+use crate::pose::Pose;
+use crate::simulation::cancel_motion;
+use crate::storage::Storage;
+
+/// File in the data directory holding all checkpoints.
+pub const CHECKPOINTS_FILE: &str = "checkpoints.json";
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Checkpoint {
+    pub name: String,
+    /// The robot saved, or absent when the checkpoint holds the whole world.
+    #[schema(value_type = Option<u32>)]
+    pub robot_id: Option<RobotId>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at_ms: u64,
+    #[schema(value_type = BTreeMap<u32, Pose>)]
+    pub robots: BTreeMap<RobotId, Pose>,
+}
+
+/// Named checkpoints, written through to the data directory on every change.
+pub struct Checkpoints {
+    storage: Storage,
+    checkpoints: Mutex<BTreeMap<String, Checkpoint>>,
+}
+
+impl Checkpoints {
+    pub fn load(storage: Storage) -> io::Result<Self> {
+        let checkpoints = storage.read_json(CHECKPOINTS_FILE)?.unwrap_or_default();
+        Ok(Checkpoints {
+            storage,
+            checkpoints: Mutex::new(checkpoints),
+        })
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut BTreeMap<String, Checkpoint>) -> T) -> io::Result<T> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        let mut updated = checkpoints.clone();
+        let result = f(&mut updated);
+        self.storage.write_json(CHECKPOINTS_FILE, &updated)?;
+        *checkpoints = updated;
+        Ok(result)
+    }
+
+    fn get(&self, name: &str) -> Option<Checkpoint> {
+        self.checkpoints.lock().unwrap().get(name).cloned()
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CheckpointScope {
+    /// Save only this robot; without it the whole world is saved.
+    #[param(value_type = Option<u32>)]
+    pub robot_id: Option<RobotId>,
+}
+
+fn invalid_name(name: &str) -> Option<HttpResponse> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (!valid).then(|| {
+        HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "checkpoint names are 1 to {MAX_NAME_LEN} letters, digits, '-' or '_'"
+        )))
+    })
+}
+
+fn checkpoint_not_found(name: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(format!("checkpoint {name} not found")))
+}
+
+fn storage_failed(err: io::Error) -> HttpResponse {
+    tracing::error!(%err, "checkpoint write failed");
+    HttpResponse::InternalServerError().json(ErrorResponse::new(format!(
+        "could not save checkpoints: {err}"
+    )))
+}
+
+/// Save the current pose of one robot, or of every robot, under a name.
+///
+/// An existing checkpoint with the same name is replaced.
+#[utoipa::path(
+    put,
+    path = "/checkpoints/{name}",
+    params(("name" = String, Path, description = "Checkpoint name"), CheckpointScope),
+    responses(
+        (status = 200, description = "Checkpoint saved", body = Checkpoint),
+        (status = 400, description = "Invalid name", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse)
+    )
+)]
+pub async fn save_checkpoint(
+    checkpoints: web::Data<Checkpoints>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    path: web::Path<String>,
+    scope: web::Query<CheckpointScope>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if let Some(response) = authorize(
+        &http,
+        Permission::Reposition,
+        scope.robot_id.unwrap_or(DEFAULT_ROBOT),
+    ) {
+        return response;
+    }
+    if let Some(response) = invalid_name(&name) {
+        return response;
+    }
+    let saved: BTreeMap<RobotId, Pose> = {
+        let robots = robots.lock();
+        match scope.robot_id {
+            Some(id) => match robots.get(&id) {
+                Some(entry) => BTreeMap::from([(id, Pose::from(&entry.robot))]),
+                None => return robot_not_found(id),
+            },
+            None => robots
+                .iter()
+                .map(|(id, entry)| (*id, Pose::from(&entry.robot)))
+                .collect(),
+        }
+    };
+    let checkpoint = Checkpoint {
+        name: name.clone(),
+        robot_id: scope.robot_id,
+        created_at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        robots: saved,
+    };
+    match checkpoints.update(|checkpoints| checkpoints.insert(name, checkpoint.clone())) {
+        Ok(_) => {
+            tracing::info!(
+                checkpoint = %checkpoint.name,
+                robots = checkpoint.robots.len(),
+                "checkpoint saved"
+            );
+            HttpResponse::Ok().json(checkpoint)
+        }
+        Err(err) => storage_failed(err),
+    }
+}
+
+/// All saved checkpoints, ordered by name.
+#[utoipa::path(
+    get,
+    path = "/checkpoints",
+    responses(
+        (status = 200, description = "Saved checkpoints", body = Vec<Checkpoint>)
+    )
+)]
+pub async fn list_checkpoints(
+    checkpoints: web::Data<Checkpoints>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    let checkpoints: Vec<Checkpoint> = checkpoints
+        .checkpoints
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    HttpResponse::Ok().json(checkpoints)
+}
+
+/// Get one checkpoint.
+#[utoipa::path(
+    get,
+    path = "/checkpoints/{name}",
+    params(("name" = String, Path, description = "Checkpoint name")),
+    responses(
+        (status = 200, description = "The checkpoint", body = Checkpoint),
+        (status = 404, description = "Unknown checkpoint", body = ErrorResponse)
+    )
+)]
+pub async fn get_checkpoint(
+    checkpoints: web::Data<Checkpoints>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    let Some(checkpoint) = checkpoints.get(&path) else {
+        return checkpoint_not_found(&path);
+    };
+    if let Some(response) = authorize(
+        &http,
+        Permission::Read,
+        checkpoint.robot_id.unwrap_or(DEFAULT_ROBOT),
+    ) {
+        return response;
+    }
+    HttpResponse::Ok().json(checkpoint)
+}
+
+/// Delete a checkpoint.
+#[utoipa::path(
+    delete,
+    path = "/checkpoints/{name}",
+    params(("name" = String, Path, description = "Checkpoint name")),
+    responses(
+        (status = 204, description = "Checkpoint deleted"),
+        (status = 404, description = "Unknown checkpoint", body = ErrorResponse)
+    )
+)]
+pub async fn delete_checkpoint(
+    checkpoints: web::Data<Checkpoints>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    let Some(checkpoint) = checkpoints.get(&path) else {
+        return checkpoint_not_found(&path);
+    };
+    if let Some(response) = authorize(
+        &http,
+        Permission::Reposition,
+        checkpoint.robot_id.unwrap_or(DEFAULT_ROBOT),
+    ) {
+        return response;
+    }
+    match checkpoints.update(|checkpoints| checkpoints.remove(path.as_str())) {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => storage_failed(err),
+    }
+}
+
+/// Put the robots back where the checkpoint saved them.
+///
+/// Restoring a world checkpoint also removes robots placed after it was saved.
+#[utoipa::path(
+    post,
+    path = "/checkpoints/{name}/restore",
+    params(("name" = String, Path, description = "Checkpoint name")),
+    responses(
+        (status = 200, description = "Poses after the restore", body = Checkpoint),
+        (status = 404, description = "Unknown checkpoint", body = ErrorResponse)
+    )
+)]
+pub async fn restore_checkpoint(
+    checkpoints: web::Data<Checkpoints>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    let Some(checkpoint) = checkpoints.get(&path) else {
+        return checkpoint_not_found(&path);
+    };
+    // A world restore replaces every robot, so it takes the permission a reset does.
+    let permission = match checkpoint.robot_id {
+        Some(_) => Permission::Reposition,
+        None => Permission::Reset,
+    };
+    if let Some(response) = authorize(
+        &http,
+        permission,
+        checkpoint.robot_id.unwrap_or(DEFAULT_ROBOT),
+    ) {
+        return response;
+    }
+
+    let mut robots = robots.lock();
+    let removed: Vec<RobotId> = match checkpoint.robot_id {
+        Some(_) => Vec::new(),
+        None => robots
+            .keys()
+            .filter(|id| !checkpoint.robots.contains_key(id))
+            .copied()
+            .collect(),
+    };
+    // Log every change before touching a robot, so a failed write leaves the world as it was.
+    let changes = removed
+        .iter()
+        .map(|&id| (id, Change::Removed, None))
+        .chain(checkpoint.robots.iter().map(|(&id, &pose)| {
+            let robot = robots
+                .get(&id)
+                .map_or_else(|| RobotModel::from(pose), |entry| entry.robot.clone());
+            (
+                id,
+                Change::Repositioned { pose },
+                battery_level(&http, id, &robot),
+            )
+        }))
+        .collect();
+    if let Some(response) = record_events(&http, changes) {
+        return response;
+    }
+    for id in removed {
+        cancel_motion(&http, id);
+        robots.remove(&id);
+    }
+    for (&id, &pose) in &checkpoint.robots {
+        cancel_motion(&http, id);
+        robots
+            .entry(id)
+            .and_modify(|entry| *entry.update() = RobotModel::from(pose))
+            .or_insert_with(|| VersionedRobot::new(RobotModel::from(pose)));
+    }
+    tracing::info!(
+        checkpoint = %checkpoint.name,
+        robots = checkpoint.robots.len(),
+        "checkpoint restored"
+    );
+    HttpResponse::Ok().json(checkpoint)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::checkpoints::{
+        delete_checkpoint, list_checkpoints, restore_checkpoint, save_checkpoint, Checkpoints,
+    };
+    use crate::controller::{move_robot, reposition_robot, RobotState};
+    use crate::storage::TempStorage;
+
+    #[actix_web::test]
+    async fn test_save_and_restore_world() {
+        let storage = TempStorage::new("checkpoints");
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(web::Data::new(Checkpoints::load(storage.clone()).unwrap()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot))
+                .route("/checkpoints", web::get().to(list_checkpoints))
+                .route("/checkpoints/{name}", web::put().to(save_checkpoint))
+                .route("/checkpoints/{name}", web::delete().to(delete_checkpoint))
+                .route(
+                    "/checkpoints/{name}/restore",
+                    web::post().to(restore_checkpoint),
+                ),
+        )
+        .await;
+        let post = |uri: &str, body: serde_json::Value| {
+            TestRequest::post().uri(uri).set_json(body).to_request()
+        };
+
+        call_service(
+            &app,
+            post("/move_robot", serde_json::json!({ "instructions": "RA" })),
+        )
+        .await;
+        let req = TestRequest::put().uri("/checkpoints/start").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+        let req = TestRequest::put()
+            .uri("/checkpoints/bad%20name")
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        call_service(
+            &app,
+            post("/move_robot", serde_json::json!({ "instructions": "AAL" })),
+        )
+        .await;
+        call_service(
+            &app,
+            post(
+                "/reposition_robot?robot_id=2",
+                serde_json::json!({ "x": 5, "y": 5, "facing": "South" }),
+            ),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/checkpoints/second?robot_id=2")
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let req = TestRequest::post()
+            .uri("/checkpoints/start/restore")
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+        {
+            let robots = robots.lock();
+            assert_eq!(robots.len(), 1);
+            assert_eq!(
+                crate::pose::Pose::from(&robots[&0].robot),
+                serde_json::from_value(serde_json::json!({ "x": 1, "y": 0, "facing": "East" }))
+                    .unwrap()
+            );
+        }
+
+        let req = TestRequest::delete().uri("/checkpoints/start").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 204);
+        let req = TestRequest::get().uri("/checkpoints").to_request();
+        let listed: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(listed[0]["name"], "second");
+
+        let reloaded = Checkpoints::load(storage.clone()).unwrap();
+        assert!(reloaded.get("start").is_none());
+        assert_eq!(reloaded.get("second").unwrap().robots.len(), 1);
+    }
+}
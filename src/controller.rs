@@ -1,182 +1,1099 @@
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
 
-use actix_web::{web, HttpResponse, Responder};
-use serde::Deserialize;
-use utoipa::{OpenApi, ToSchema};
+use actix_web::http::header::{self, EntityTag, IfMatch, IfNoneMatch};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
+use crate::auth::{authorize, Permission, SecurityAddon};
+use crate::battery::{battery_level, Batteries, BatteryDepleted, Plan};
+use crate::checkpoints::Checkpoint;
+use crate::events::{record_event, Change, Event, Mismatch, Verification};
+use crate::health::{BuildInfo, Check, Health, Readiness};
+use crate::history::{HistoryEntry, PastPose};
+use crate::jobs::{JobRequest, JobState, JobStatus};
+use crate::limits::{charge_instructions, Usage};
+use crate::metrics::{record_executed, record_rejected, Histogram, Rejection, LOCK_WAIT_BUCKETS};
+use crate::pose::{Heading, Pose};
+use crate::rhai_scripts::{RhaiScript, RhaiScriptUpload};
+use crate::script::{ScriptRequest, ScriptResult, StopReason};
+use crate::sensors::Sensors;
+use crate::simulation::{
+    cancel_motion, ClockStatus, InterpolatedPose, MotionStatus, Simulation, SpeedChange,
+};
 use crate::solutions::*;
+use crate::world::{Blocked, Bounds, Cell, Reading, World, WorldMap};
+
+pub type RobotId = u32;
+
+/// The robot every route falls back to when no `robot_id` is given.
+pub const DEFAULT_ROBOT: RobotId = 0;
 
 #[cfg(feature = "no_pattern")]
-pub struct RobotState(pub Mutex<Robot>);
+pub type RobotModel = Robot;
 
 #[cfg(feature = "type_state")]
-pub struct RobotState(pub Mutex<RobotWithFace>);
+pub type RobotModel = RobotWithFace;
+
+/// Name of the solution compiled into the server.
+#[cfg(feature = "no_pattern")]
+pub const ENGINE: &str = "no_pattern";
+
+#[cfg(feature = "type_state")]
+pub const ENGINE: &str = "type_state";
+
+/// A robot together with a version that grows with every mutation.
+#[derive(Clone)]
+pub struct VersionedRobot {
+    pub robot: RobotModel,
+    pub version: u64,
+}
+
+impl VersionedRobot {
+    pub fn new(robot: RobotModel) -> Self {
+        VersionedRobot { robot, version: 1 }
+    }
+
+    /// Borrow the robot for a mutation, bumping its version.
+    pub fn update(&mut self) -> &mut RobotModel {
+        self.version += 1;
+        &mut self.robot
+    }
+
+    pub fn etag(&self) -> EntityTag {
+        EntityTag::new_strong(self.version.to_string())
+    }
+
+    /// Answer with the robot's pose and, when batteries are enabled, its level.
+    pub fn respond(&self, battery: Option<f64>) -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header(header::ETag(self.etag()))
+            .json(WithBattery {
+                pose: &self.robot,
+                battery,
+            })
+    }
+}
+
+/// A pose with the robot's battery level alongside its fields.
+#[derive(Serialize)]
+pub struct WithBattery<'a, T> {
+    #[serde(flatten)]
+    pub pose: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<f64>,
+}
+
+/// How much of `program` the robot's battery powers after its queued motion, when batteries are enabled.
+fn battery_plan(
+    req: &HttpRequest,
+    robot_id: RobotId,
+    entry: &VersionedRobot,
+    queued: &[char],
+    program: &[char],
+) -> Option<Plan> {
+    let batteries = req.app_data::<web::Data<Batteries>>()?;
+    Some(batteries.plan(robot_id, Pose::from(&entry.robot), queued, program))
+}
+
+fn battery_depleted(
+    entry: &VersionedRobot,
+    pose: RobotModel,
+    steps: usize,
+    battery: f64,
+) -> HttpResponse {
+    HttpResponse::Conflict()
+        .insert_header(header::ETag(entry.etag()))
+        .json(BatteryDepleted {
+            error: format!("battery ran out after {steps} instructions"),
+            steps,
+            pose,
+            battery,
+        })
+}
+
+/// Where `program` would first run into the world map, or the ends of `i32`, after the
+/// robot's queued motion.
+fn collision(
+    req: &HttpRequest,
+    entry: &VersionedRobot,
+    queued: &[char],
+    program: &[char],
+) -> Option<Blocked> {
+    let start = queued
+        .iter()
+        .fold(Pose::from(&entry.robot), |pose, &movement| {
+            pose.step(movement)
+        });
+    match req.app_data::<web::Data<World>>() {
+        Some(world) => world.map().collision(start, program),
+        None => WorldMap::default().collision(start, program),
+    }
+}
+
+fn collided(
+    entry: &VersionedRobot,
+    pose: RobotModel,
+    steps: usize,
+    blocked: Blocked,
+) -> HttpResponse {
+    HttpResponse::Conflict()
+        .insert_header(header::ETag(entry.etag()))
+        .json(Collision {
+            error: format!(
+                "collision with {} at {},{} after {steps} instructions",
+                blocked.reading.name(),
+                blocked.cell.x,
+                blocked.cell.y
+            ),
+            steps,
+            pose,
+            cell: blocked.cell,
+            reading: blocked.reading,
+        })
+}
+
+pub struct RobotState {
+    robots: Mutex<HashMap<RobotId, VersionedRobot>>,
+    pub lock_wait: Histogram,
+}
+
+/// The robots the server starts with before anything is recorded.
+pub fn initial_robots() -> HashMap<RobotId, VersionedRobot> {
+    HashMap::from([(DEFAULT_ROBOT, VersionedRobot::new(RobotModel::default()))])
+}
+
+impl RobotState {
+    #[cfg(test)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        RobotState::restore(initial_robots())
+    }
+
+    /// Start from previously saved robots.
+    pub fn restore(robots: HashMap<RobotId, VersionedRobot>) -> Self {
+        RobotState {
+            robots: Mutex::new(robots),
+            lock_wait: Histogram::new(&LOCK_WAIT_BUCKETS),
+        }
+    }
+
+    /// Lock all robots, recording how long the lock took to acquire.
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<RobotId, VersionedRobot>> {
+        let started = Instant::now();
+        let robots = self.robots.lock().unwrap();
+        self.lock_wait.observe(started.elapsed());
+        robots
+    }
+
+    /// Whether a handler panicked while holding the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.robots.is_poisoned()
+    }
+}
 
 #[derive(Deserialize, ToSchema)]
 pub struct MoveInstruction {
     pub instructions: String,
+    /// Return the predicted pose without moving the robot.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Only move if the robot is currently at this pose.
+    pub expected_start: Option<RobotModel>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PoseConflict {
+    pub error: String,
+    pub actual: RobotModel,
+}
+
+/// The program stopped before an `A` into an obstacle or off the arena.
+#[derive(Serialize, ToSchema)]
+pub struct Collision {
+    pub error: String,
+    /// Instructions carried out before the collision.
+    pub steps: usize,
+    pub pose: RobotModel,
+    /// The cell the robot would have driven into.
+    pub cell: Cell,
+    pub reading: Reading,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RobotSelector {
+    /// Robot to operate on, defaults to robot `0`.
+    #[param(value_type = Option<u32>)]
+    pub robot_id: Option<RobotId>,
+}
+
+impl RobotSelector {
+    pub fn id(&self) -> RobotId {
+        self.robot_id.unwrap_or(DEFAULT_ROBOT)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl ToString) -> Self {
+        ErrorResponse {
+            error: error.to_string(),
+        }
+    }
+}
+
+pub fn robot_not_found(id: RobotId) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(format!("robot {id} not found")))
+}
+
+/// Answer `412` when the request's `If-Match` does not match the robot's current version.
+pub fn precondition_failed(
+    req: &HttpRequest,
+    current: Option<&VersionedRobot>,
+) -> Option<HttpResponse> {
+    let matches = match req.get_header::<IfMatch>() {
+        None => true,
+        Some(IfMatch::Any) => current.is_some(),
+        Some(IfMatch::Items(tags)) => {
+            current.is_some_and(|current| tags.iter().any(|tag| tag.strong_eq(&current.etag())))
+        }
+    };
+    if matches {
+        return None;
+    }
+    let mut response = HttpResponse::PreconditionFailed();
+    if let Some(current) = current {
+        response.insert_header(header::ETag(current.etag()));
+    }
+    Some(response.json(ErrorResponse::new("robot version does not match If-Match")))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidInstruction {
+    pub position: usize,
+    pub instruction: char,
+}
+
+impl fmt::Display for InvalidInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid instruction '{}' at position {}",
+            self.instruction, self.position
+        )
+    }
+}
+
+/// Validate one instruction. Whitespace is accepted and skipped.
+pub fn parse_instruction(
+    position: usize,
+    instruction: char,
+) -> Result<Option<char>, InvalidInstruction> {
+    match instruction {
+        'L' | 'R' | 'A' => Ok(Some(instruction)),
+        _ if instruction.is_ascii_whitespace() => Ok(None),
+        _ => Err(InvalidInstruction {
+            position,
+            instruction,
+        }),
+    }
+}
+
+/// Check that a program only contains `L`, `R` and `A`.
+pub fn parse_program(instructions: &str) -> Result<Vec<char>, InvalidInstruction> {
+    instructions
+        .chars()
+        .enumerate()
+        .filter_map(|(position, instruction)| parse_instruction(position, instruction).transpose())
+        .collect()
 }
 
 /// Move the robot based on a series of instructions (`L`, `R`, `A`).
-#[cfg(feature = "no_pattern")]
 #[utoipa::path(
     post,
     path = "/move_robot",
+    params(
+        RobotSelector,
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the first result when the request is retried")
+    ),
     request_body = MoveInstruction,
     responses(
-        (status = 200, description = "Robot moved successfully", body = Robot)
+        (status = 200, description = "Robot moved successfully", body = RobotModel),
+        (status = 202, description = "Instructions queued in simulation mode", body = MotionStatus),
+        (status = 400, description = "Invalid program", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse),
+        (status = 409, description = "Robot is not at expected_start, a BatteryDepleted when the battery ran out part way, or a Collision before an obstacle or the arena edge", body = PoseConflict),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 413, description = "Too many instructions for one request", body = ErrorResponse),
+        (status = 422, description = "Idempotency-Key reused with a different request", body = ErrorResponse),
+        (status = 429, description = "Daily instruction quota exceeded", body = ErrorResponse)
     )
 )]
+#[tracing::instrument(skip_all, fields(robot_id = selector.id()))]
 pub async fn move_robot(
     data: web::Data<RobotState>,
+    selector: web::Query<RobotSelector>,
+    http: HttpRequest,
     req: web::Json<MoveInstruction>,
 ) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    for movement in req.instructions.chars() {
-        robot.execute(movement);
+    if let Some(response) = authorize(&http, Permission::Move, selector.id()) {
+        return response;
+    }
+    let program = match parse_program(&req.instructions) {
+        Ok(program) => program,
+        Err(err) => {
+            record_rejected(&http, Rejection::Invalid, 1);
+            tracing::debug!(%err, "rejected program");
+            return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+        }
+    };
+    let mut robots = data.lock();
+    let Some(entry) = robots.get_mut(&selector.id()) else {
+        return robot_not_found(selector.id());
+    };
+    if let Some(response) = precondition_failed(&http, Some(entry)) {
+        return response;
+    }
+    if let Some(expected) = &req.expected_start {
+        if *expected != entry.robot {
+            return HttpResponse::Conflict()
+                .insert_header(header::ETag(entry.etag()))
+                .json(PoseConflict {
+                    error: "robot is not at the expected start pose".to_string(),
+                    actual: entry.robot.clone(),
+                });
+        }
+    }
+    let simulation = http.app_data::<web::Data<Simulation>>();
+    let queued = simulation
+        .map(|simulation| simulation.queued(selector.id()))
+        .unwrap_or_default();
+    let blocked = collision(&http, entry, &queued, &program);
+    let clear = blocked.map_or(program.len(), |blocked| blocked.steps);
+    let plan = battery_plan(&http, selector.id(), entry, &queued, &program[..clear]);
+    let runnable = plan.as_ref().map_or(clear, |plan| plan.steps);
+    let depleted = plan.as_ref().filter(|_| runnable < clear);
+    let blocked = blocked.filter(|_| runnable == clear);
+    if req.dry_run {
+        let mut predicted = entry.robot.clone();
+        for &movement in queued.iter().chain(&program[..runnable]) {
+            predicted.execute(movement);
+        }
+        tracing::info!(instructions = runnable, pose = ?predicted, "dry run");
+        if let Some(plan) = depleted {
+            return battery_depleted(entry, predicted, runnable, plan.level);
+        }
+        if let Some(blocked) = blocked {
+            return collided(entry, predicted, runnable, blocked);
+        }
+        return HttpResponse::Ok()
+            .insert_header(header::ETag(entry.etag()))
+            .json(WithBattery {
+                pose: &predicted,
+                battery: plan.map(|plan| plan.level),
+            });
     }
-    HttpResponse::Ok().json(robot.clone())
+    if let Some(response) = charge_instructions(&http, program.len(), runnable) {
+        return response;
+    }
+    if let Some(simulation) = simulation {
+        let motion = simulation.enqueue(selector.id(), &program[..runnable]);
+        tracing::info!(
+            instructions = runnable,
+            queued = motion.queued,
+            "robot motion queued"
+        );
+        if let Some(plan) = depleted {
+            return battery_depleted(entry, entry.robot.clone(), runnable, plan.level);
+        }
+        if let Some(blocked) = blocked {
+            return collided(entry, entry.robot.clone(), runnable, blocked);
+        }
+        return HttpResponse::Accepted()
+            .insert_header(header::ETag(entry.etag()))
+            .json(motion);
+    }
+    let program = &program[..runnable];
+    if !program.is_empty() || plan.is_none() {
+        let change = Change::Moved {
+            instructions: program.iter().collect(),
+        };
+        let battery = plan.as_ref().map(|plan| plan.level);
+        if let Some(response) = record_event(&http, selector.id(), change, battery) {
+            return response;
+        }
+        let robot = entry.update();
+        tracing::info_span!("execute", instructions = program.len()).in_scope(|| {
+            for &movement in program {
+                robot.execute(movement);
+            }
+        });
+    }
+    record_executed(&http, program.len());
+    tracing::info!(instructions = program.len(), pose = ?entry.robot, "robot moved");
+    if let (Some(batteries), Some(plan)) = (http.app_data::<web::Data<Batteries>>(), &plan) {
+        batteries.commit(selector.id(), plan);
+    }
+    if let Some(plan) = depleted {
+        tracing::info!(steps = runnable, "battery ran out");
+        return battery_depleted(entry, entry.robot.clone(), runnable, plan.level);
+    }
+    if let Some(blocked) = blocked {
+        tracing::info!(%blocked, "robot stopped before a collision");
+        return collided(entry, entry.robot.clone(), runnable, blocked);
+    }
+    entry.respond(plan.map(|plan| plan.level))
 }
 
-#[cfg(feature = "type_state")]
-#[utoipa::path(
-    post,
-    path = "/move_robot",
-    request_body = MoveInstruction,
-    responses(
-        (status = 200, description = "Robot facing", body = RobotWithFace),
-    )
-)]
-pub async fn move_robot(
-    data: web::Data<RobotState>,
-    req: web::Json<MoveInstruction>,
-) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    for movement in req.instructions.chars() {
-        match movement {
-            'L' => robot.turn_left(),
-            'R' => robot.turn_right(),
-            'A' => robot.advance(),
-            _ => (),
+/// Decode `bytes`, turning invalid sequences into U+FFFD, and say how many bytes at the end
+/// start a character that is not complete yet.
+fn decode_utf8(mut bytes: &[u8]) -> (String, usize) {
+    let mut text = String::new();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return (text, 0);
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let Some(invalid) = err.error_len() else {
+                    return (text, rest.len());
+                };
+                text.push(char::REPLACEMENT_CHARACTER);
+                bytes = &rest[invalid..];
+            }
         }
     }
-    HttpResponse::Ok().json(robot.clone())
 }
 
-/// Set the robot's position manually.
-#[cfg(feature = "no_pattern")]
+#[derive(Serialize, ToSchema)]
+pub struct StreamResult {
+    pub pose: RobotModel,
+    pub steps: usize,
+}
+
+/// Move the robot with a plain-text program, applying instructions as the body arrives.
+///
+/// Instructions before an invalid one stay applied; the error reports how many were.
+/// In simulation mode they are queued instead and the result shows the settled pose.
 #[utoipa::path(
     post,
-    path = "/reposition_robot",
-    request_body = Robot,
+    path = "/move_robot/stream",
+    params(RobotSelector),
+    request_body(content = String, content_type = "text/plain"),
     responses(
-        (status = 200, description = "Robot repositioned successfully", body = Robot)
+        (status = 200, description = "Robot moved successfully", body = StreamResult),
+        (status = 400, description = "Invalid program", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse),
+        (status = 409, description = "Battery ran out part way, or a Collision before an obstacle or the arena edge", body = BatteryDepleted),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 413, description = "Too many instructions for one request", body = ErrorResponse),
+        (status = 429, description = "Daily instruction quota exceeded", body = ErrorResponse)
     )
 )]
-pub async fn reposition_robot(
+#[tracing::instrument(skip_all, fields(robot_id = selector.id()))]
+pub async fn move_robot_stream(
     data: web::Data<RobotState>,
-    req: web::Json<Robot>,
-) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    robot.x = req.x;
-    robot.y = req.y;
-    robot.facing = req.facing.clone();
-    HttpResponse::Ok().json(robot.clone())
+    selector: web::Query<RobotSelector>,
+    http: HttpRequest,
+    mut body: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Some(response) = authorize(&http, Permission::Move, selector.id()) {
+        return Ok(response);
+    }
+    let id = selector.id();
+    {
+        let robots = data.lock();
+        let Some(entry) = robots.get(&id) else {
+            return Ok(robot_not_found(id));
+        };
+        if let Some(response) = precondition_failed(&http, Some(entry)) {
+            return Ok(response);
+        }
+    }
+
+    // Positions count characters, as in the JSON endpoint, so a character split across
+    // chunks waits in `pending` for the rest of its bytes.
+    let mut pending = Vec::new();
+    let mut position = 0;
+    let mut steps = 0;
+    let mut finished = false;
+    while !finished {
+        match body.next().await {
+            Some(chunk) => pending.extend_from_slice(&chunk?),
+            None => finished = true,
+        }
+        let (mut text, cut) = decode_utf8(&pending);
+        pending.drain(..pending.len() - cut);
+        if finished && !pending.is_empty() {
+            text.push(char::REPLACEMENT_CHARACTER);
+        }
+        let mut chunk_program = Vec::new();
+        let mut invalid = None;
+        for instruction in text.chars() {
+            match parse_instruction(position, instruction) {
+                Ok(Some(movement)) => chunk_program.push(movement),
+                Ok(None) => (),
+                Err(err) => {
+                    invalid = Some(err);
+                    break;
+                }
+            }
+            position += 1;
+        }
+        let chunk_steps = chunk_program.len();
+        let mut robots = data.lock();
+        let Some(entry) = robots.get_mut(&id) else {
+            return Ok(robot_not_found(id));
+        };
+        let simulation = http.app_data::<web::Data<Simulation>>();
+        let queued = simulation
+            .map(|simulation| simulation.queued(id))
+            .unwrap_or_default();
+        let blocked = collision(&http, entry, &queued, &chunk_program);
+        let clear = blocked.map_or(chunk_steps, |blocked| blocked.steps);
+        let plan = battery_plan(&http, id, entry, &queued, &chunk_program[..clear]);
+        let runnable = plan.as_ref().map_or(clear, |plan| plan.steps);
+        let chunk_program = &chunk_program[..runnable];
+        if let Some(response) = charge_instructions(&http, steps + chunk_steps, runnable) {
+            return Ok(response);
+        }
+        if let Some(simulation) = simulation {
+            simulation.enqueue(id, chunk_program);
+        } else if !chunk_program.is_empty() {
+            let change = Change::Moved {
+                instructions: chunk_program.iter().collect(),
+            };
+            let battery = plan.as_ref().map(|plan| plan.level);
+            if let Some(response) = record_event(&http, id, change, battery) {
+                return Ok(response);
+            }
+            let robot = entry.update();
+            for &movement in chunk_program {
+                robot.execute(movement);
+            }
+            if let (Some(batteries), Some(plan)) = (http.app_data::<web::Data<Batteries>>(), &plan)
+            {
+                batteries.commit(id, plan);
+            }
+            record_executed(&http, runnable);
+        }
+        steps += runnable;
+
+        if let Some(plan) = plan.filter(|_| runnable < clear) {
+            tracing::info!(steps, "battery ran out during streamed program");
+            return Ok(battery_depleted(
+                entry,
+                entry.robot.clone(),
+                steps,
+                plan.level,
+            ));
+        }
+        if let Some(blocked) = blocked {
+            tracing::info!(steps, %blocked, "streamed program stopped before a collision");
+            return Ok(collided(entry, entry.robot.clone(), steps, blocked));
+        }
+
+        if let Some(err) = invalid {
+            record_rejected(&http, Rejection::Invalid, 1);
+            tracing::debug!(%err, steps, "rejected streamed program");
+            return Ok(HttpResponse::BadRequest()
+                .json(ErrorResponse::new(format!("{err} ({steps} steps applied)"))));
+        }
+    }
+
+    let robots = data.lock();
+    match robots.get(&id) {
+        Some(entry) => {
+            tracing::info!(instructions = steps, pose = ?entry.robot, "robot moved");
+            Ok(HttpResponse::Ok()
+                .insert_header(header::ETag(entry.etag()))
+                .json(StreamResult {
+                    pose: entry.robot.clone(),
+                    steps,
+                }))
+        }
+        None => Ok(robot_not_found(id)),
+    }
 }
 
-#[cfg(feature = "type_state")]
+/// Set the robot's position manually, placing a new robot if the id is unknown.
 #[utoipa::path(
     post,
     path = "/reposition_robot",
-    request_body = RobotWithFace,
+    params(
+        RobotSelector,
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the first result when the request is retried")
+    ),
+    request_body = RobotModel,
     responses(
-        (status = 200, description = "Robot facing", body = RobotWithFace),
+        (status = 200, description = "Robot repositioned successfully", body = RobotModel),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 422, description = "Idempotency-Key reused with a different request", body = ErrorResponse)
     )
 )]
+#[tracing::instrument(skip_all, fields(robot_id = selector.id()))]
 pub async fn reposition_robot(
     data: web::Data<RobotState>,
-    req: web::Json<RobotWithFace>,
+    selector: web::Query<RobotSelector>,
+    http: HttpRequest,
+    req: web::Json<RobotModel>,
 ) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    *robot = req.clone();
-    HttpResponse::Ok().json(robot.clone())
+    if let Some(response) = authorize(&http, Permission::Reposition, selector.id()) {
+        return response;
+    }
+    let mut robots = data.lock();
+    if let Some(response) = precondition_failed(&http, robots.get(&selector.id())) {
+        return response;
+    }
+    let change = Change::Repositioned {
+        pose: Pose::from(&*req),
+    };
+    // The battery goes with the robot, settled at the pose it leaves.
+    let battery = battery_level(
+        &http,
+        selector.id(),
+        robots
+            .get(&selector.id())
+            .map_or(&*req, |entry| &entry.robot),
+    );
+    if let Some(response) = record_event(&http, selector.id(), change, battery) {
+        return response;
+    }
+    cancel_motion(&http, selector.id());
+    let entry = robots
+        .entry(selector.id())
+        .and_modify(|entry| *entry.update() = req.clone())
+        .or_insert_with(|| VersionedRobot::new(req.clone()));
+    tracing::info!(pose = ?entry.robot, "robot repositioned");
+    entry.respond(battery)
 }
 
 /// Reset the robot to its initial position.
-#[cfg(feature = "no_pattern")]
 #[utoipa::path(
     post,
     path = "/reset_robot",
+    params(
+        RobotSelector,
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the first result when the request is retried")
+    ),
     responses(
-        (status = 200, description = "Robot reset successfully", body = Robot)
+        (status = 200, description = "Robot reset successfully", body = RobotModel),
+        (status = 404, description = "Unknown robot", body = ErrorResponse),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 422, description = "Idempotency-Key reused with a different request", body = ErrorResponse)
     )
 )]
-pub async fn reset_robot(data: web::Data<RobotState>) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    *robot = Robot {
-        x: 0,
-        y: 0,
-        facing: Direction::North,
+#[tracing::instrument(skip_all, fields(robot_id = selector.id()))]
+pub async fn reset_robot(
+    data: web::Data<RobotState>,
+    selector: web::Query<RobotSelector>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Reset, selector.id()) {
+        return response;
+    }
+    let mut robots = data.lock();
+    let Some(entry) = robots.get_mut(&selector.id()) else {
+        return robot_not_found(selector.id());
     };
+    if let Some(response) = precondition_failed(&http, Some(entry)) {
+        return response;
+    }
+    let batteries = http.app_data::<web::Data<Batteries>>();
+    let battery = batteries.map(|batteries| batteries.capacity());
+    if let Some(response) = record_event(&http, selector.id(), Change::Reset, battery) {
+        return response;
+    }
+    cancel_motion(&http, selector.id());
+    if let Some(batteries) = batteries {
+        batteries.refill(selector.id());
+    }
+    *entry.update() = RobotModel::default();
+    tracing::info!(pose = ?entry.robot, "robot reset");
 
-    HttpResponse::Ok().json(robot.clone())
-}
-
-#[cfg(feature = "type_state")]
-#[utoipa::path(
-    post,
-    path = "/reset_robot",
-    responses(
-        (status = 200, description = "Robot facing North", body = RobotWithFace),
-    )
-)]
-pub async fn reset_robot(data: web::Data<RobotState>) -> impl Responder {
-    let mut robot = data.0.lock().unwrap();
-    *robot = RobotWithFace::North(Robot::new(0, 0));
-
-    HttpResponse::Ok().json(robot.clone())
+    entry.respond(battery)
 }
 
 /// Get the robot's current position and direction.
-#[cfg(feature = "no_pattern")]
 #[utoipa::path(
     get,
     path = "/robot_position",
+    params(RobotSelector),
     responses(
-        (status = 200, description = "Current robot position", body = Robot)
+        (status = 200, description = "Current robot position; an InterpolatedPose in simulation mode", body = RobotModel),
+        (status = 304, description = "Robot unchanged since the If-None-Match version"),
+        (status = 404, description = "Unknown robot", body = ErrorResponse)
     )
 )]
-pub async fn robot_position(data: web::Data<RobotState>) -> impl Responder {
-    let robot = data.0.lock().unwrap();
-    HttpResponse::Ok().json(robot.clone())
-}
-
-#[cfg(feature = "type_state")]
-#[utoipa::path(
-    get,
-    path = "/robot_position",
-    responses(
-        (status = 200, description = "Robot facing North", body = RobotWithFace),
-    )
-)]
-pub async fn robot_position(data: web::Data<RobotState>) -> impl Responder {
-    let robot = data.0.lock().unwrap();
-    HttpResponse::Ok().json(robot.clone())
+#[tracing::instrument(skip_all, fields(robot_id = selector.id()))]
+pub async fn robot_position(
+    data: web::Data<RobotState>,
+    selector: web::Query<RobotSelector>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, selector.id()) {
+        return response;
+    }
+    let robots = data.lock();
+    let Some(entry) = robots.get(&selector.id()) else {
+        return robot_not_found(selector.id());
+    };
+    let battery = battery_level(&http, selector.id(), &entry.robot);
+    if let Some(simulation) = http.app_data::<web::Data<Simulation>>() {
+        return HttpResponse::Ok()
+            .insert_header(header::ETag(entry.etag()))
+            .json(WithBattery {
+                pose: &simulation.interpolate(selector.id(), &entry.robot),
+                battery,
+            });
+    }
+    let unchanged = match http.get_header::<IfNoneMatch>() {
+        None => false,
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&entry.etag())),
+    };
+    if unchanged {
+        return HttpResponse::NotModified()
+            .insert_header(header::ETag(entry.etag()))
+            .finish();
+    }
+    entry.respond(battery)
 }
 
 /// OpenAPI documentation setup.
 #[cfg(feature = "no_pattern")]
 #[derive(OpenApi)]
 #[openapi(
-    paths(move_robot, reposition_robot, reset_robot, robot_position),
-    components(schemas(Robot, MoveInstruction, Direction))
+    paths(
+        move_robot,
+        move_robot_stream,
+        reposition_robot,
+        reset_robot,
+        robot_position,
+        crate::jobs::create_job,
+        crate::jobs::job_status,
+        crate::jobs::cancel_job,
+        crate::limits::limit_usage,
+        crate::metrics::metrics,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::health::version,
+        crate::events::list_events,
+        crate::events::verify_events,
+        crate::history::position_at,
+        crate::history::history,
+        crate::checkpoints::save_checkpoint,
+        crate::checkpoints::list_checkpoints,
+        crate::checkpoints::get_checkpoint,
+        crate::checkpoints::delete_checkpoint,
+        crate::checkpoints::restore_checkpoint,
+        crate::simulation::clock_status,
+        crate::simulation::pause_clock,
+        crate::simulation::resume_clock,
+        crate::simulation::step_clock,
+        crate::simulation::set_speed,
+        crate::world::get_world,
+        crate::world::put_world,
+        crate::sensors::robot_sensors,
+        crate::script::run_script,
+        crate::rhai_scripts::save_rhai_script,
+        crate::rhai_scripts::list_rhai_scripts,
+        crate::rhai_scripts::get_rhai_script,
+        crate::rhai_scripts::delete_rhai_script,
+        crate::rhai_scripts::run_rhai_script,
+        crate::render::render_text,
+        crate::render::render_svg
+    ),
+    components(schemas(
+        Robot,
+        MoveInstruction,
+        PoseConflict,
+        StreamResult,
+        Direction,
+        ErrorResponse,
+        JobRequest,
+        JobStatus,
+        JobState,
+        Usage,
+        Health,
+        Readiness,
+        Check,
+        BuildInfo,
+        Event,
+        Change,
+        Pose,
+        Heading,
+        Mismatch,
+        Verification,
+        PastPose,
+        HistoryEntry,
+        Checkpoint,
+        MotionStatus,
+        InterpolatedPose,
+        ClockStatus,
+        SpeedChange,
+        WorldMap,
+        Cell,
+        BatteryDepleted,
+        Collision,
+        Bounds,
+        Reading,
+        Sensors,
+        ScriptRequest,
+        ScriptResult,
+        StopReason,
+        RhaiScript,
+        RhaiScriptUpload
+    )),
+    modifiers(&SecurityAddon),
+    security(("api_key" = []), ("bearer" = []))
 )]
 pub struct ApiDoc;
 
 #[cfg(feature = "type_state")]
 #[derive(OpenApi)]
 #[openapi(
-    paths(move_robot, reposition_robot, reset_robot, robot_position),
-    components(schemas(RobotWithFace, MoveInstruction))
+    paths(
+        move_robot,
+        move_robot_stream,
+        reposition_robot,
+        reset_robot,
+        robot_position,
+        crate::jobs::create_job,
+        crate::jobs::job_status,
+        crate::jobs::cancel_job,
+        crate::limits::limit_usage,
+        crate::metrics::metrics,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::health::version,
+        crate::events::list_events,
+        crate::events::verify_events,
+        crate::history::position_at,
+        crate::history::history,
+        crate::checkpoints::save_checkpoint,
+        crate::checkpoints::list_checkpoints,
+        crate::checkpoints::get_checkpoint,
+        crate::checkpoints::delete_checkpoint,
+        crate::checkpoints::restore_checkpoint,
+        crate::simulation::clock_status,
+        crate::simulation::pause_clock,
+        crate::simulation::resume_clock,
+        crate::simulation::step_clock,
+        crate::simulation::set_speed,
+        crate::world::get_world,
+        crate::world::put_world,
+        crate::sensors::robot_sensors,
+        crate::script::run_script,
+        crate::rhai_scripts::save_rhai_script,
+        crate::rhai_scripts::list_rhai_scripts,
+        crate::rhai_scripts::get_rhai_script,
+        crate::rhai_scripts::delete_rhai_script,
+        crate::rhai_scripts::run_rhai_script,
+        crate::render::render_text,
+        crate::render::render_svg
+    ),
+    components(schemas(
+        RobotWithFace,
+        MoveInstruction,
+        PoseConflict,
+        StreamResult,
+        ErrorResponse,
+        JobRequest,
+        JobStatus,
+        JobState,
+        Usage,
+        Health,
+        Readiness,
+        Check,
+        BuildInfo,
+        Event,
+        Change,
+        Pose,
+        Heading,
+        Mismatch,
+        Verification,
+        PastPose,
+        HistoryEntry,
+        Checkpoint,
+        MotionStatus,
+        InterpolatedPose,
+        ClockStatus,
+        SpeedChange,
+        WorldMap,
+        Cell,
+        BatteryDepleted,
+        Collision,
+        Bounds,
+        Reading,
+        Sensors,
+        ScriptRequest,
+        ScriptResult,
+        StopReason,
+        RhaiScript,
+        RhaiScriptUpload
+    )),
+    modifiers(&SecurityAddon),
+    security(("api_key" = []), ("bearer" = []))
 )]
 pub struct ApiDoc;
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{
+        call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+    };
+    use actix_web::{web, App};
+
+    use crate::controller::{
+        decode_utf8, move_robot, move_robot_stream, parse_program, robot_position,
+        InvalidInstruction, RobotState,
+    };
+
+    #[test]
+    fn test_parse_program() {
+        assert_eq!(parse_program("RAALAL").unwrap().len(), 6);
+        assert_eq!(
+            parse_program("RAXL"),
+            Err(InvalidInstruction {
+                position: 2,
+                instruction: 'X'
+            })
+        );
+        assert_eq!(parse_program("RA AL\n").unwrap(), vec!['R', 'A', 'A', 'L']);
+    }
+
+    #[actix_web::test]
+    async fn test_stream_matches_json() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/move_robot/stream", web::post().to(move_robot_stream)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/move_robot/stream?robot_id=0")
+            .insert_header(("content-type", "text/plain"))
+            .set_payload("RAALAL\n")
+            .to_request();
+        let resp: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["steps"], 6);
+
+        for program in ["LAZ", "L\u{e9}A", "LA\u{1f916}"] {
+            let req = TestRequest::post()
+                .uri("/move_robot/stream")
+                .set_payload(program)
+                .to_request();
+            let stream_error = call_service(&app, req).await;
+            assert_eq!(stream_error.status(), 400);
+
+            let req = TestRequest::post()
+                .uri("/move_robot")
+                .set_json(serde_json::json!({ "instructions": program }))
+                .to_request();
+            let json_error: serde_json::Value = call_and_read_body_json(&app, req).await;
+            let stream_error: serde_json::Value = read_body_json(stream_error).await;
+            assert!(
+                stream_error["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with(json_error["error"].as_str().unwrap()),
+                "{program}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_utf8_across_chunks() {
+        let bytes = "A\u{e9}".as_bytes();
+        assert_eq!(decode_utf8(&bytes[..2]), ("A".to_string(), 1));
+        assert_eq!(decode_utf8(&bytes[1..]), ("\u{e9}".to_string(), 0));
+        assert_eq!(decode_utf8(b"A\xffR"), ("A\u{fffd}R".to_string(), 0));
+    }
+
+    #[actix_web::test]
+    async fn test_etag_preconditions() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/robot_position", web::get().to(robot_position)),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/robot_position").to_request()).await;
+        assert_eq!(resp.headers().get("etag").unwrap(), "\"1\"");
+
+        let req = TestRequest::get()
+            .uri("/robot_position")
+            .insert_header(("if-none-match", "\"1\""))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 304);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .insert_header(("if-match", "\"1\""))
+            .set_json(serde_json::json!({ "instructions": "A" }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("etag").unwrap(), "\"2\"");
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .insert_header(("if-match", "\"1\""))
+            .set_json(serde_json::json!({ "instructions": "A" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 412);
+    }
+
+    #[actix_web::test]
+    async fn test_dry_run_and_expected_start() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/robot_position", web::get().to(robot_position)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "AA", "dry_run": true }))
+            .to_request();
+        let predicted: serde_json::Value = call_and_read_body_json(&app, req).await;
+        let req = TestRequest::get().uri("/robot_position").to_request();
+        let current: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_ne!(predicted, current);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "A", "expected_start": predicted }))
+            .to_request();
+        let conflict = call_service(&app, req).await;
+        assert_eq!(conflict.status(), 409);
+        let conflict: serde_json::Value = read_body_json(conflict).await;
+        assert_eq!(conflict["actual"], current);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "AA", "expected_start": current }))
+            .to_request();
+        let moved: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(moved, predicted);
+    }
+}
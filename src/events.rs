@@ -0,0 +1,787 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{
+    initial_robots, ErrorResponse, RobotId, RobotModel, RobotState, VersionedRobot, DEFAULT_ROBOT,
+    ENGINE,
+};
+use crate::pose::Pose;
+use crate::storage::Storage;
+
+/// Robots saved every [`SNAPSHOT_INTERVAL`] events and at shutdown, with the last event they include.
+pub const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// Append-only log of every mutation, one JSON event per line.
+pub const EVENT_LOG_FILE: &str = "events.jsonl";
+
+/// Events logged between two snapshots, which bounds how much recovery and history replay.
+pub const SNAPSHOT_INTERVAL: Seq = 1000;
+
+/// Snapshots kept in memory for history; replays from before them start at the beginning.
+pub const SNAPSHOTS_KEPT: usize = 16;
+
+pub type Seq = u64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    Moved {
+        instructions: String,
+    },
+    Repositioned {
+        pose: Pose,
+    },
+    Reset,
+    /// The robot was taken out of the world, e.g. by restoring a world checkpoint.
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Event {
+    #[schema(value_type = u64)]
+    pub seq: Seq,
+    /// Milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    #[serde(flatten)]
+    pub change: Change,
+    /// Battery level right after the change, when batteries are enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<f64>,
+}
+
+impl Event {
+    /// Steps this event takes: one per instruction, one for a reposition or reset.
+    pub fn steps(&self) -> u64 {
+        match &self.change {
+            Change::Moved { instructions } => instructions.chars().count() as u64,
+            Change::Repositioned { .. } | Change::Reset | Change::Removed => 1,
+        }
+    }
+
+    pub fn apply(&self, robots: &mut HashMap<RobotId, VersionedRobot>) {
+        match &self.change {
+            Change::Moved { instructions } => {
+                if let Some(entry) = robots.get_mut(&self.robot_id) {
+                    let robot = entry.update();
+                    for movement in instructions.chars() {
+                        robot.execute(movement);
+                    }
+                }
+            }
+            Change::Repositioned { pose } => {
+                robots
+                    .entry(self.robot_id)
+                    .and_modify(|entry| *entry.update() = RobotModel::from(*pose))
+                    .or_insert_with(|| VersionedRobot::new(RobotModel::from(*pose)));
+            }
+            Change::Reset => {
+                if let Some(entry) = robots.get_mut(&self.robot_id) {
+                    *entry.update() = RobotModel::default();
+                }
+            }
+            Change::Removed => {
+                robots.remove(&self.robot_id);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedRobot {
+    pub pose: Pose,
+    pub version: u64,
+}
+
+impl SavedRobot {
+    pub fn robot(&self) -> VersionedRobot {
+        VersionedRobot {
+            robot: RobotModel::from(self.pose),
+            version: self.version,
+        }
+    }
+}
+
+/// What the log holds for one robot up to a point, so replays can start there.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Progress {
+    /// Steps taken, see [`Event::steps`].
+    pub steps: u64,
+    /// Cells driven into, where each reposition and reset counts as one.
+    pub cells: u64,
+    /// Sequence number and time of its last event.
+    pub last_event: Option<(Seq, u64)>,
+}
+
+impl Progress {
+    fn record(&mut self, event: &Event) {
+        self.steps += event.steps();
+        self.cells += match &event.change {
+            Change::Moved { instructions } => instructions.matches('A').count() as u64,
+            Change::Repositioned { .. } | Change::Reset => 1,
+            Change::Removed => 0,
+        };
+        self.last_event = Some((event.seq, event.at_ms));
+    }
+}
+
+/// Robots as of event `seq`, stored by pose so any engine can load them.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub seq: Seq,
+    /// Time of event `seq` in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub at_ms: u64,
+    /// Length of the event log up to event `seq`, where recovery starts reading.
+    #[serde(default)]
+    pub offset: Option<u64>,
+    pub robots: BTreeMap<RobotId, SavedRobot>,
+    #[serde(default)]
+    pub progress: BTreeMap<RobotId, Progress>,
+}
+
+impl Snapshot {
+    pub fn progress(&self, robot_id: RobotId) -> Progress {
+        self.progress.get(&robot_id).copied().unwrap_or_default()
+    }
+}
+
+/// The robots rebuilt from the log, kept up to date as events are appended.
+struct Replayed {
+    robots: HashMap<RobotId, VersionedRobot>,
+    progress: HashMap<RobotId, Progress>,
+}
+
+impl Replayed {
+    fn new(snapshot: Option<&Snapshot>) -> Self {
+        match snapshot {
+            Some(snapshot) => Replayed {
+                robots: snapshot
+                    .robots
+                    .iter()
+                    .map(|(id, saved)| (*id, saved.robot()))
+                    .collect(),
+                progress: snapshot
+                    .progress
+                    .iter()
+                    .map(|(id, progress)| (*id, *progress))
+                    .collect(),
+            },
+            None => Replayed {
+                robots: initial_robots(),
+                progress: HashMap::new(),
+            },
+        }
+    }
+
+    fn apply(&mut self, event: &Event) {
+        event.apply(&mut self.robots);
+        self.progress
+            .entry(event.robot_id)
+            .or_default()
+            .record(event);
+    }
+}
+
+struct Log {
+    file: File,
+    /// Bytes in the file, i.e. where the next event starts.
+    len: u64,
+    /// Sequence number and time of the last event.
+    last: (Seq, u64),
+    /// Events after the latest snapshot. Older ones are read back from the file.
+    recent: Vec<Event>,
+    replayed: Replayed,
+    /// The newest [`SNAPSHOTS_KEPT`] snapshots, oldest first.
+    snapshots: VecDeque<Arc<Snapshot>>,
+}
+
+impl Log {
+    fn capture(&self) -> Snapshot {
+        let saved = |entry: &VersionedRobot| SavedRobot {
+            pose: Pose::from(&entry.robot),
+            version: entry.version,
+        };
+        Snapshot {
+            seq: self.last.0,
+            at_ms: self.last.1,
+            offset: Some(self.len),
+            robots: self
+                .replayed
+                .robots
+                .iter()
+                .map(|(id, entry)| (*id, saved(entry)))
+                .collect(),
+            progress: self
+                .replayed
+                .progress
+                .iter()
+                .map(|(id, progress)| (*id, *progress))
+                .collect(),
+        }
+    }
+
+    /// Events after the latest snapshot start here.
+    fn recent_after(&self) -> Seq {
+        self.snapshots.back().map_or(0, |snapshot| snapshot.seq)
+    }
+}
+
+pub struct EventLog {
+    path: PathBuf,
+    storage: Storage,
+    log: Mutex<Log>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl EventLog {
+    /// Open the log in the data directory, starting from the last snapshot and
+    /// replaying the events written after it.
+    ///
+    /// A torn last line left by a crash is dropped.
+    pub fn open(storage: &Storage) -> io::Result<Self> {
+        fs::create_dir_all(storage.dir())?;
+        let path = storage.dir().join(EVENT_LOG_FILE);
+        // Snapshots without an offset predate periodic snapshots; the log is replayed in full then.
+        let snapshot = storage
+            .read_json::<Snapshot>(SNAPSHOT_FILE)?
+            .filter(|snapshot| snapshot.offset.is_some());
+        let (after, offset) = snapshot.as_ref().map_or((0, 0), |snapshot| {
+            (snapshot.seq, snapshot.offset.unwrap_or(0))
+        });
+
+        let mut contents = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_to_end(&mut contents)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let file_len = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if file_len < offset {
+            return Err(invalid_data(format!(
+                "{EVENT_LOG_FILE} is shorter than {SNAPSHOT_FILE} expects"
+            )));
+        }
+
+        let mut replayed = Replayed::new(snapshot.as_ref());
+        let mut last = snapshot
+            .as_ref()
+            .map_or((0, 0), |snapshot| (snapshot.seq, snapshot.at_ms));
+        let mut recent = Vec::new();
+        let mut valid_len = 0;
+        let mut lines = contents.split_inclusive(|byte| *byte == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_slice::<Event>(line) {
+                Ok(event) if line.ends_with(b"\n") => {
+                    valid_len += line.len();
+                    if event.seq > after {
+                        replayed.apply(&event);
+                        last = (event.seq, event.at_ms);
+                        recent.push(event);
+                    }
+                }
+                _ if lines.peek().is_none() => {
+                    tracing::warn!(after_seq = last.0, "dropping torn event log tail");
+                }
+                Err(err) => return Err(invalid_data(err)),
+                Ok(_) => unreachable!("only the last line can lack a newline"),
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = offset + valid_len as u64;
+        file.set_len(len)?;
+        tracing::info!(
+            snapshot_seq = after,
+            replayed = recent.len(),
+            robots = replayed.robots.len(),
+            "event log opened"
+        );
+        Ok(EventLog {
+            path,
+            storage: storage.clone(),
+            log: Mutex::new(Log {
+                file,
+                len,
+                last,
+                recent,
+                replayed,
+                snapshots: snapshot.map(Arc::new).into_iter().collect(),
+            }),
+        })
+    }
+
+    /// Write `change` to the log before it is applied. Call with the robot lock held.
+    pub fn append(
+        &self,
+        robot_id: RobotId,
+        change: Change,
+        battery: Option<f64>,
+    ) -> io::Result<Event> {
+        let mut events = self.append_all(vec![(robot_id, change, battery)])?;
+        Ok(events.remove(0))
+    }
+
+    /// Write several changes with a single write, so either all of them are logged or none.
+    ///
+    /// Every [`SNAPSHOT_INTERVAL`] events a snapshot is saved as well.
+    pub fn append_all(
+        &self,
+        changes: Vec<(RobotId, Change, Option<f64>)>,
+    ) -> io::Result<Vec<Event>> {
+        let mut log = self.log.lock().unwrap();
+        let at_ms = now_ms();
+        let events: Vec<Event> = changes
+            .into_iter()
+            .zip(log.last.0 + 1..)
+            .map(|((robot_id, change, battery), seq)| Event {
+                seq,
+                at_ms,
+                robot_id,
+                change,
+                battery,
+            })
+            .collect();
+        let mut lines = Vec::new();
+        for event in &events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+        if let Err(err) = log.file.write_all(&lines).and_then(|()| log.file.flush()) {
+            // Cut off whatever part was written, so the next event does not follow a torn line.
+            if let Err(truncate_err) = log.file.set_len(log.len) {
+                tracing::error!(%truncate_err, "could not truncate torn event log write");
+            }
+            return Err(err);
+        }
+        log.len += lines.len() as u64;
+        for event in &events {
+            log.replayed.apply(event);
+            log.last = (event.seq, event.at_ms);
+        }
+        log.recent.extend(events.iter().cloned());
+
+        if log.last.0 - log.recent_after() >= SNAPSHOT_INTERVAL {
+            // The events are safe in the log, so a failed snapshot only delays the next one.
+            if let Err(err) = self.save_snapshot(&mut log) {
+                tracing::warn!(%err, "periodic snapshot failed");
+            }
+        }
+        Ok(events)
+    }
+
+    fn save_snapshot(&self, log: &mut Log) -> io::Result<Arc<Snapshot>> {
+        if let Some(latest) = log
+            .snapshots
+            .back()
+            .filter(|latest| latest.seq == log.last.0)
+        {
+            return Ok(latest.clone());
+        }
+        let snapshot = Arc::new(log.capture());
+        self.storage.write_json(SNAPSHOT_FILE, &*snapshot)?;
+        log.recent.clear();
+        if log.snapshots.len() == SNAPSHOTS_KEPT {
+            log.snapshots.pop_front();
+        }
+        log.snapshots.push_back(snapshot.clone());
+        tracing::debug!(seq = snapshot.seq, "snapshot written");
+        Ok(snapshot)
+    }
+
+    /// Save the robots as of the last event to the data directory.
+    pub fn snapshot(&self) -> io::Result<Arc<Snapshot>> {
+        self.save_snapshot(&mut self.log.lock().unwrap())
+    }
+
+    pub fn last_seq(&self) -> Seq {
+        self.log.lock().unwrap().last.0
+    }
+
+    /// The robots as rebuilt from the log, which is how a restart finds them.
+    pub fn robots(&self) -> HashMap<RobotId, VersionedRobot> {
+        self.log.lock().unwrap().replayed.robots.clone()
+    }
+
+    /// What the log holds for one robot up to the last event.
+    pub fn progress(&self, robot_id: RobotId) -> Progress {
+        let log = self.log.lock().unwrap();
+        log.replayed
+            .progress
+            .get(&robot_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Events after `after`, optionally only those of one robot.
+    ///
+    /// Events from before the latest snapshot are read back from the file.
+    pub fn events(&self, robot_id: Option<RobotId>, after: Seq) -> io::Result<Vec<Event>> {
+        let matches =
+            |event: &Event| event.seq > after && robot_id.is_none_or(|id| event.robot_id == id);
+        let (offset, len) = {
+            let log = self.log.lock().unwrap();
+            if after >= log.recent_after() {
+                return Ok(log
+                    .recent
+                    .iter()
+                    .filter(|event| matches(event))
+                    .cloned()
+                    .collect());
+            }
+            let offset = log
+                .snapshots
+                .iter()
+                .rev()
+                .find(|snapshot| snapshot.seq <= after)
+                .map_or(0, |snapshot| snapshot.offset.unwrap_or(0));
+            (offset, log.len)
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut events = Vec::new();
+        for line in BufReader::new(file.take(len - offset)).lines() {
+            let event: Event = serde_json::from_str(&line?).map_err(invalid_data)?;
+            if matches(&event) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// The latest snapshot `usable` accepts, with the events logged after it, optionally
+    /// only those of one robot. Without such a snapshot the events start at the beginning.
+    pub fn since_snapshot(
+        &self,
+        robot_id: Option<RobotId>,
+        usable: impl Fn(&Snapshot) -> bool,
+    ) -> io::Result<(Option<Arc<Snapshot>>, Vec<Event>)> {
+        let snapshot = {
+            let log = self.log.lock().unwrap();
+            log.snapshots
+                .iter()
+                .rev()
+                .find(|snapshot| usable(snapshot))
+                .cloned()
+        };
+        let after = snapshot.as_ref().map_or(0, |snapshot| snapshot.seq);
+        Ok((snapshot, self.events(robot_id, after)?))
+    }
+
+    /// Replay the log up to event `until` from the initial robots with the compiled engine.
+    pub fn rebuild(&self, until: Seq) -> io::Result<HashMap<RobotId, VersionedRobot>> {
+        let mut robots = initial_robots();
+        for event in self.events(None, 0)? {
+            if event.seq > until {
+                break;
+            }
+            event.apply(&mut robots);
+        }
+        Ok(robots)
+    }
+}
+
+/// The robots as the log leaves them, to start the server with.
+pub fn recover(log: &EventLog) -> RobotState {
+    RobotState::restore(log.robots())
+}
+
+/// Print the poses rebuilt from the whole log as JSON, for comparing engines.
+///
+/// Run `robot replay` with each engine feature against the same data directory
+/// and diff the output.
+pub fn print_replay(storage: &Storage) -> io::Result<()> {
+    let log = EventLog::open(storage)?;
+    let poses: BTreeMap<RobotId, Pose> = log
+        .rebuild(Seq::MAX)?
+        .iter()
+        .map(|(id, entry)| (*id, Pose::from(&entry.robot)))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&poses)?);
+    Ok(())
+}
+
+/// Log `change` for `robot_id` when an event log is registered.
+///
+/// Answers `500` if the event cannot be written; the change must then not be applied.
+pub fn record_event(
+    req: &HttpRequest,
+    robot_id: RobotId,
+    change: Change,
+    battery: Option<f64>,
+) -> Option<HttpResponse> {
+    let log = req.app_data::<web::Data<EventLog>>()?;
+    let err = log.append(robot_id, change, battery).err()?;
+    tracing::error!(%err, "event log write failed");
+    Some(
+        HttpResponse::InternalServerError()
+            .json(ErrorResponse::new(format!("could not record event: {err}"))),
+    )
+}
+
+/// Log several changes at once, see [`record_event`]; none are logged if one cannot be.
+pub fn record_events(
+    req: &HttpRequest,
+    changes: Vec<(RobotId, Change, Option<f64>)>,
+) -> Option<HttpResponse> {
+    let log = req.app_data::<web::Data<EventLog>>()?;
+    let err = log.append_all(changes).err()?;
+    tracing::error!(%err, "event log write failed");
+    Some(
+        HttpResponse::InternalServerError().json(ErrorResponse::new(format!(
+            "could not record events: {err}"
+        ))),
+    )
+}
+
+/// Answer `500` when logged events cannot be read back.
+pub fn log_unreadable(err: io::Error) -> HttpResponse {
+    tracing::error!(%err, "event log read failed");
+    HttpResponse::InternalServerError().json(ErrorResponse::new(format!(
+        "could not read event log: {err}"
+    )))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct EventQuery {
+    /// Only events of this robot.
+    #[param(value_type = Option<u32>)]
+    pub robot_id: Option<RobotId>,
+    /// Only events with a larger sequence number.
+    #[param(value_type = Option<u64>)]
+    pub after: Option<Seq>,
+}
+
+/// The audit trail of robot mutations, oldest first.
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(EventQuery),
+    responses(
+        (status = 200, description = "Logged events", body = Vec<Event>)
+    )
+)]
+pub async fn list_events(
+    log: web::Data<EventLog>,
+    http: HttpRequest,
+    query: web::Query<EventQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(
+        &http,
+        Permission::Read,
+        query.robot_id.unwrap_or(DEFAULT_ROBOT),
+    ) {
+        return response;
+    }
+    match log.events(query.robot_id, query.after.unwrap_or(0)) {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => log_unreadable(err),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Mismatch {
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    pub live: Option<Pose>,
+    pub replayed: Option<Pose>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Verification {
+    pub engine: &'static str,
+    /// Sequence number of the last event replayed.
+    #[schema(value_type = u64)]
+    pub events: Seq,
+    pub consistent: bool,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Rebuild every robot from the full log and compare with the live state.
+#[utoipa::path(
+    get,
+    path = "/events/verify",
+    responses(
+        (status = 200, description = "Replay compared with the live robots", body = Verification)
+    )
+)]
+pub async fn verify_events(
+    log: web::Data<EventLog>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    let (live, seq) = {
+        let robots = robots.lock();
+        (robots.clone(), log.last_seq())
+    };
+    let replayed = match log.rebuild(seq) {
+        Ok(replayed) => replayed,
+        Err(err) => return log_unreadable(err),
+    };
+
+    let mut ids: Vec<RobotId> = live.keys().chain(replayed.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let mismatches: Vec<Mismatch> = ids
+        .into_iter()
+        .map(|robot_id| Mismatch {
+            robot_id,
+            live: live.get(&robot_id).map(|entry| Pose::from(&entry.robot)),
+            replayed: replayed
+                .get(&robot_id)
+                .map(|entry| Pose::from(&entry.robot)),
+        })
+        .filter(|mismatch| mismatch.live != mismatch.replayed)
+        .collect();
+    HttpResponse::Ok().json(Verification {
+        engine: ENGINE,
+        events: seq,
+        consistent: mismatches.is_empty(),
+        mismatches,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::{move_robot, reposition_robot, reset_robot, RobotState};
+    use crate::events::{
+        recover, verify_events, Change, EventLog, EVENT_LOG_FILE, SNAPSHOTS_KEPT, SNAPSHOT_INTERVAL,
+    };
+    use crate::pose::{Heading, Pose};
+    use crate::storage::TempStorage;
+
+    #[actix_web::test]
+    async fn test_replay_matches_live_state() {
+        let storage = TempStorage::new("events");
+        let log = web::Data::new(EventLog::open(&storage).unwrap());
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(log.clone())
+                .app_data(robots.clone())
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot))
+                .route("/reset_robot", web::post().to(reset_robot))
+                .route("/events/verify", web::get().to(verify_events)),
+        )
+        .await;
+
+        let post = |uri: &str, body: serde_json::Value| {
+            TestRequest::post().uri(uri).set_json(body).to_request()
+        };
+        call_service(
+            &app,
+            post(
+                "/move_robot",
+                serde_json::json!({ "instructions": "RAALAL" }),
+            ),
+        )
+        .await;
+        assert_eq!(log.snapshot().unwrap().seq, 1);
+        call_service(
+            &app,
+            post(
+                "/reposition_robot?robot_id=3",
+                serde_json::json!({ "x": 7, "y": 3, "facing": "North" }),
+            ),
+        )
+        .await;
+        call_service(
+            &app,
+            post(
+                "/move_robot?robot_id=3",
+                serde_json::json!({ "instructions": "RA" }),
+            ),
+        )
+        .await;
+        call_service(&app, TestRequest::post().uri("/reset_robot").to_request()).await;
+
+        let req = TestRequest::get().uri("/events/verify").to_request();
+        let verification: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(verification["events"], 4);
+        assert_eq!(verification["consistent"], true);
+
+        // A crash mid-write leaves a torn line, which recovery drops.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(storage.dir().join(EVENT_LOG_FILE))
+            .unwrap();
+        file.write_all(b"{\"seq\":5,\"at_ms\"").unwrap();
+
+        let reopened = EventLog::open(&storage).unwrap();
+        assert_eq!(reopened.last_seq(), 4);
+        // The event before the snapshot is read back from the file.
+        assert_eq!(reopened.events(None, 0).unwrap().len(), 4);
+        let recovered = recover(&reopened);
+        let recovered = recovered.lock();
+        assert_eq!(
+            Pose::from(&recovered[&3].robot),
+            Pose {
+                x: 8,
+                y: 3,
+                facing: Heading::East
+            }
+        );
+        assert_eq!(
+            Pose::from(&recovered[&0].robot),
+            Pose {
+                x: 0,
+                y: 0,
+                facing: Heading::North
+            }
+        );
+        assert_eq!(recovered[&0].version, robots.lock()[&0].version);
+    }
+
+    #[test]
+    fn test_only_the_newest_snapshots_are_kept() {
+        let storage = TempStorage::new("events");
+        let log = EventLog::open(&storage).unwrap();
+        let total = SNAPSHOT_INTERVAL * (SNAPSHOTS_KEPT as u64 + 2);
+        for _ in 0..total {
+            let turned = Change::Moved {
+                instructions: "L".to_string(),
+            };
+            log.append(0, turned, None).unwrap();
+        }
+        {
+            let inner = log.log.lock().unwrap();
+            assert_eq!(inner.snapshots.len(), SNAPSHOTS_KEPT);
+            assert_eq!(inner.snapshots[0].seq, 3 * SNAPSHOT_INTERVAL);
+            assert!(inner.recent.is_empty());
+        }
+        // Events from before the snapshots kept are still read back from the file.
+        assert_eq!(log.events(Some(0), 0).unwrap().len() as u64, total);
+    }
+}
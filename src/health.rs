@@ -0,0 +1,151 @@
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::controller::{RobotState, ENGINE};
+use crate::storage::Storage;
+
+#[derive(Serialize, ToSchema)]
+pub struct Health {
+    pub status: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        Check {
+            name,
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub engine: &'static str,
+    pub git_hash: &'static str,
+}
+
+/// The process is up and serving requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "Process alive", body = Health)
+    )
+)]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(Health { status: "ok" })
+}
+
+/// Whether the server can take traffic: robots loaded, lock usable, storage writable.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Ready", body = Readiness),
+        (status = 503, description = "Not ready", body = Readiness)
+    )
+)]
+pub async fn readyz(
+    robots: Option<web::Data<RobotState>>,
+    storage: Option<web::Data<Storage>>,
+) -> impl Responder {
+    let mut checks = vec![
+        Check::new(
+            "state_loaded",
+            robots
+                .as_ref()
+                .map(|_| ())
+                .ok_or_else(|| "robot state is not registered".to_string()),
+        ),
+        Check::new(
+            "state_lock",
+            match &robots {
+                Some(robots) if robots.is_poisoned() => {
+                    Err("robot state lock is poisoned by a panicked handler".to_string())
+                }
+                _ => Ok(()),
+            },
+        ),
+    ];
+    if let Some(storage) = &storage {
+        checks.push(Check::new(
+            "storage_writable",
+            storage
+                .check_writable()
+                .map_err(|err| format!("{}: {err}", storage.dir().display())),
+        ));
+    }
+
+    let ready = checks.iter().all(|check| check.ok);
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.json(Readiness { ready, checks })
+}
+
+/// Crate version, compiled engine and git revision.
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses(
+        (status = 200, description = "Build information", body = BuildInfo)
+    )
+)]
+pub async fn version() -> impl Responder {
+    HttpResponse::Ok().json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        engine: ENGINE,
+        git_hash: env!("GIT_HASH"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::RobotState;
+    use crate::health::readyz;
+
+    #[actix_web::test]
+    async fn test_readyz_fails_on_poisoned_lock() {
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/readyz").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _robots = robots.lock();
+            panic!("handler panicked while holding the lock");
+        }));
+
+        let req = TestRequest::get().uri("/readyz").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 503);
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{initial_robots, ErrorResponse, RobotId, VersionedRobot};
+use crate::events::{log_unreadable, Change, Event, EventLog, Progress, Seq, Snapshot};
+use crate::pose::Pose;
+use crate::world::Cell;
+
+#[derive(Deserialize, IntoParams)]
+pub struct PointInTime {
+    /// Milliseconds since the Unix epoch.
+    pub at: Option<u64>,
+    /// Number of steps the robot had taken. Each instruction, reposition and reset is one step.
+    pub step: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PastPose {
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    pub pose: Pose,
+    /// Steps taken up to this pose.
+    pub steps: u64,
+    /// The last event that contributed, absent for the initial pose.
+    #[schema(value_type = Option<u64>)]
+    pub seq: Option<Seq>,
+    pub at_ms: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct TimeRange {
+    /// Earliest event time in milliseconds since the Unix epoch, inclusive.
+    pub from: Option<u64>,
+    /// Latest event time in milliseconds since the Unix epoch, inclusive.
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub event: Event,
+    /// Pose right after the event.
+    pub pose: Option<Pose>,
+}
+
+/// The robot as it was before anything was logged for it.
+fn initial(robot_id: RobotId) -> HashMap<RobotId, VersionedRobot> {
+    let mut robots = initial_robots();
+    robots.retain(|id, _| *id == robot_id);
+    robots
+}
+
+/// Where a replay of `robot_id` starts: as of `snapshot`, or before anything was logged.
+fn start(
+    snapshot: Option<&Snapshot>,
+    robot_id: RobotId,
+) -> (HashMap<RobotId, VersionedRobot>, Progress) {
+    match snapshot {
+        Some(snapshot) => {
+            let robots = snapshot
+                .robots
+                .get(&robot_id)
+                .map(|saved| (robot_id, saved.robot()))
+                .into_iter()
+                .collect();
+            (robots, snapshot.progress(robot_id))
+        }
+        None => (initial(robot_id), Progress::default()),
+    }
+}
+
+fn pose(robots: &HashMap<RobotId, VersionedRobot>, robot_id: RobotId) -> Option<Pose> {
+    robots.get(&robot_id).map(|entry| Pose::from(&entry.robot))
+}
+
+fn robot_unknown_at(robot_id: RobotId) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(format!(
+        "robot {robot_id} did not exist at that point"
+    )))
+}
+
+/// The last `length` cells the robot drove through, oldest first, rebuilt from the event log.
+///
+/// Turning in place adds no cell. A new path starts wherever the robot was repositioned or reset.
+pub fn trail(log: &EventLog, robot_id: RobotId, length: usize) -> io::Result<Vec<Vec<Cell>>> {
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    // Start at the latest snapshot the robot has driven through enough cells since.
+    let cells = log.progress(robot_id).cells;
+    let (snapshot, events) = log.since_snapshot(Some(robot_id), |snapshot| {
+        cells.saturating_sub(snapshot.progress(robot_id).cells) >= length as u64
+    })?;
+    // Only the last `length` cells are kept, each with the number of the path it is on.
+    let mut recent: VecDeque<(usize, Cell)> = VecDeque::new();
+    let mut visit = |path: usize, cell: Cell| {
+        if recent.back() == Some(&(path, cell)) {
+            return;
+        }
+        if recent.len() == length {
+            recent.pop_front();
+        }
+        recent.push_back((path, cell));
+    };
+    let (mut robots, _) = start(snapshot.as_deref(), robot_id);
+    let mut path = 0;
+    if let Some(pose) = pose(&robots, robot_id) {
+        visit(path, Cell::from(pose));
+    }
+    for event in events {
+        match (&event.change, robots.get(&robot_id)) {
+            (Change::Moved { instructions }, Some(entry)) => {
+                let mut robot = entry.robot.clone();
+                for instruction in instructions.chars() {
+                    robot.execute(instruction);
+                    visit(path, Cell::from(Pose::from(&robot)));
+                }
+                event.apply(&mut robots);
+            }
+            (Change::Moved { .. }, None) => event.apply(&mut robots),
+            _ => {
+                event.apply(&mut robots);
+                path += 1;
+                if let Some(pose) = pose(&robots, robot_id) {
+                    visit(path, Cell::from(pose));
+                }
+            }
+        }
+    }
+
+    Ok(recent
+        .make_contiguous()
+        .chunk_by(|(a, _), (b, _)| a == b)
+        .map(|cells| cells.iter().map(|(_, cell)| *cell).collect())
+        .collect())
+}
+
+/// Where the robot was at a past time or after a number of steps, rebuilt from the event log.
+#[utoipa::path(
+    get,
+    path = "/robots/{id}/position",
+    params(("id" = u32, Path, description = "Robot id"), PointInTime),
+    responses(
+        (status = 200, description = "Pose at that point", body = PastPose),
+        (status = 400, description = "Both at and step given", body = ErrorResponse),
+        (status = 404, description = "Robot did not exist yet or step beyond its history", body = ErrorResponse)
+    )
+)]
+pub async fn position_at(
+    log: web::Data<EventLog>,
+    http: HttpRequest,
+    path: web::Path<RobotId>,
+    query: web::Query<PointInTime>,
+) -> impl Responder {
+    let robot_id = *path;
+    if let Some(response) = authorize(&http, Permission::Read, robot_id) {
+        return response;
+    }
+    if query.at.is_some() && query.step.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("give either at or step"));
+    }
+
+    // Start at the latest snapshot taken before the point asked for.
+    let found = log.since_snapshot(Some(robot_id), |snapshot| match (query.at, query.step) {
+        (Some(at), _) => snapshot.at_ms <= at,
+        (_, Some(step)) => snapshot.progress(robot_id).steps < step,
+        _ => true,
+    });
+    let (snapshot, events) = match found {
+        Ok(found) => found,
+        Err(err) => return log_unreadable(err),
+    };
+    let (mut robots, progress) = start(snapshot.as_deref(), robot_id);
+    let mut steps = progress.steps;
+    let mut last = progress.last_event;
+    for event in events {
+        if query.at.is_some_and(|at| event.at_ms > at) || query.step == Some(steps) {
+            break;
+        }
+        let event_steps = event.steps();
+        match query.step {
+            Some(target) if steps + event_steps > target => {
+                // Stop inside a move, replaying only its first instructions.
+                let Change::Moved { instructions } = &event.change else {
+                    unreachable!("only moves take more than one step")
+                };
+                let partial = Event {
+                    change: Change::Moved {
+                        instructions: instructions
+                            .chars()
+                            .take((target - steps) as usize)
+                            .collect(),
+                    },
+                    ..event.clone()
+                };
+                partial.apply(&mut robots);
+                steps = target;
+            }
+            _ => {
+                event.apply(&mut robots);
+                steps += event_steps;
+            }
+        }
+        last = Some((event.seq, event.at_ms));
+    }
+
+    if let Some(target) = query.step.filter(|target| *target > steps) {
+        return HttpResponse::NotFound().json(ErrorResponse::new(format!(
+            "step {target} is beyond the {steps} steps of robot {robot_id}"
+        )));
+    }
+    let Some(pose) = pose(&robots, robot_id) else {
+        return robot_unknown_at(robot_id);
+    };
+    HttpResponse::Ok().json(PastPose {
+        robot_id,
+        pose,
+        steps,
+        seq: last.map(|(seq, _)| seq),
+        at_ms: last.map(|(_, at_ms)| at_ms),
+    })
+}
+
+/// The robot's events in a time range, each with the pose it led to.
+#[utoipa::path(
+    get,
+    path = "/robots/{id}/history",
+    params(("id" = u32, Path, description = "Robot id"), TimeRange),
+    responses(
+        (status = 200, description = "Events in the range, oldest first", body = Vec<HistoryEntry>),
+        (status = 404, description = "Robot never existed", body = ErrorResponse)
+    )
+)]
+pub async fn history(
+    log: web::Data<EventLog>,
+    http: HttpRequest,
+    path: web::Path<RobotId>,
+    query: web::Query<TimeRange>,
+) -> impl Responder {
+    let robot_id = *path;
+    if let Some(response) = authorize(&http, Permission::Read, robot_id) {
+        return response;
+    }
+    // Start at the latest snapshot taken before the range.
+    let found = log.since_snapshot(Some(robot_id), |snapshot| {
+        query.from.is_some_and(|from| snapshot.at_ms < from)
+    });
+    let (snapshot, events) = match found {
+        Ok(found) => found,
+        Err(err) => return log_unreadable(err),
+    };
+    let (mut robots, progress) = start(snapshot.as_deref(), robot_id);
+    if events.is_empty() && robots.is_empty() && progress.last_event.is_none() {
+        return robot_unknown_at(robot_id);
+    }
+
+    let mut entries = Vec::new();
+    for event in events {
+        if query.to.is_some_and(|to| event.at_ms > to) {
+            break;
+        }
+        event.apply(&mut robots);
+        if query.from.is_none_or(|from| event.at_ms >= from) {
+            entries.push(HistoryEntry {
+                pose: pose(&robots, robot_id),
+                event,
+            });
+        }
+    }
+    HttpResponse::Ok().json(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::events::{Change, EventLog, Snapshot, SNAPSHOT_FILE, SNAPSHOT_INTERVAL};
+    use crate::history::{history, position_at, trail};
+    use crate::storage::TempStorage;
+    use crate::world::Cell;
+
+    #[actix_web::test]
+    async fn test_position_at_step_and_time() {
+        let storage = TempStorage::new("history");
+        let log = EventLog::open(&storage).unwrap();
+        let moved = Change::Moved {
+            instructions: "RAALAL".to_string(),
+        };
+        log.append(0, moved, None).unwrap();
+        log.append(0, Change::Reset, None).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(log))
+                .route("/robots/{id}/position", web::get().to(position_at))
+                .route("/robots/{id}/history", web::get().to(history)),
+        )
+        .await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let resp: serde_json::Value =
+            call_and_read_body_json(&app, get("/robots/0/position?step=3")).await;
+        assert_eq!(
+            resp["pose"],
+            serde_json::json!({ "x": 2, "y": 0, "facing": "East" })
+        );
+        assert_eq!(resp["seq"], 1);
+
+        let resp: serde_json::Value =
+            call_and_read_body_json(&app, get("/robots/0/position?step=7")).await;
+        assert_eq!(
+            resp["pose"],
+            serde_json::json!({ "x": 0, "y": 0, "facing": "North" })
+        );
+        assert_eq!(resp["seq"], 2);
+
+        let resp: serde_json::Value =
+            call_and_read_body_json(&app, get("/robots/0/position?at=0")).await;
+        assert_eq!(resp["steps"], 0);
+        assert_eq!(resp["seq"], serde_json::Value::Null);
+
+        let resp = call_service(&app, get("/robots/0/position?step=8")).await;
+        assert_eq!(resp.status(), 404);
+        let resp = call_service(&app, get("/robots/0/position?at=1&step=1")).await;
+        assert_eq!(resp.status(), 400);
+
+        let resp: serde_json::Value =
+            call_and_read_body_json(&app, get("/robots/0/history?from=1")).await;
+        assert_eq!(resp.as_array().unwrap().len(), 2);
+        assert_eq!(
+            resp[0]["pose"],
+            serde_json::json!({ "x": 2, "y": 1, "facing": "West" })
+        );
+        let resp = call_service(&app, get("/robots/5/history")).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_replays_start_from_snapshots() {
+        let storage = TempStorage::new("history");
+        let log = EventLog::open(&storage).unwrap();
+        let total = 2 * SNAPSHOT_INTERVAL + 10;
+        for _ in 0..total {
+            let moved = Change::Moved {
+                instructions: "A".to_string(),
+            };
+            log.append(0, moved, None).unwrap();
+        }
+        let snapshot: Snapshot = storage.read_json(SNAPSHOT_FILE).unwrap().unwrap();
+        assert_eq!(snapshot.seq, 2 * SNAPSHOT_INTERVAL);
+        assert_eq!(snapshot.progress(0).steps, 2 * SNAPSHOT_INTERVAL);
+
+        // A reopened log only knows the last snapshot and reads older events from the file.
+        let reopened = EventLog::open(&storage).unwrap();
+        assert_eq!(reopened.last_seq(), total);
+        for log in [log, reopened] {
+            let end = |y| vec![Cell { x: 0, y }];
+            let cells: Vec<Cell> = (total as i32 - 2..=total as i32)
+                .map(|y| Cell { x: 0, y })
+                .collect();
+            assert_eq!(trail(&log, 0, 3).unwrap(), vec![cells]);
+            assert_eq!(trail(&log, 0, 1).unwrap(), vec![end(total as i32)]);
+
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(log))
+                    .route("/robots/{id}/position", web::get().to(position_at))
+                    .route("/robots/{id}/history", web::get().to(history)),
+            )
+            .await;
+            for step in [1, SNAPSHOT_INTERVAL, SNAPSHOT_INTERVAL + 1, total] {
+                let req = TestRequest::get()
+                    .uri(&format!("/robots/0/position?step={step}"))
+                    .to_request();
+                let resp: serde_json::Value = call_and_read_body_json(&app, req).await;
+                assert_eq!(resp["pose"]["y"], step);
+                assert_eq!(resp["steps"], step);
+                assert_eq!(resp["seq"], step);
+            }
+            let req = TestRequest::get().uri("/robots/0/history").to_request();
+            let resp: serde_json::Value = call_and_read_body_json(&app, req).await;
+            assert_eq!(resp.as_array().unwrap().len(), total as usize);
+        }
+    }
+}
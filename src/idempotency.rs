@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+
+use crate::auth::Principal;
+use crate::controller::ErrorResponse;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+/// How long a stored result is replayed when no window is configured.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+enum Entry {
+    InFlight {
+        fingerprint: u64,
+    },
+    Done {
+        fingerprint: u64,
+        stored_at: Instant,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: web::Bytes,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Entry::InFlight { fingerprint } | Entry::Done { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+/// Results of requests sent with an `Idempotency-Key`, kept for a fixed window.
+pub struct IdempotencyStore {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(window: Duration) -> Self {
+        IdempotencyStore {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Window from `ROBOT_IDEMPOTENCY_WINDOW_SECS`, falling back to [`DEFAULT_WINDOW`].
+    pub fn from_env() -> Self {
+        let window = std::env::var("ROBOT_IDEMPOTENCY_WINDOW_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_WINDOW);
+        IdempotencyStore::new(window)
+    }
+
+    /// Claim `key` for a new request, or return the reply for a repeated one.
+    fn begin(&self, key: &str, fingerprint: u64) -> Option<HttpResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.window;
+        entries.retain(|_, entry| match entry {
+            Entry::InFlight { .. } => true,
+            Entry::Done { stored_at, .. } => stored_at.elapsed() < window,
+        });
+
+        let Some(entry) = entries.get(key) else {
+            entries.insert(key.to_owned(), Entry::InFlight { fingerprint });
+            return None;
+        };
+        if entry.fingerprint() != fingerprint {
+            return Some(HttpResponse::UnprocessableEntity().json(ErrorResponse::new(
+                "Idempotency-Key was already used with a different request",
+            )));
+        }
+        match entry {
+            Entry::InFlight { .. } => Some(HttpResponse::Conflict().json(ErrorResponse::new(
+                "a request with this Idempotency-Key is still in progress",
+            ))),
+            Entry::Done {
+                status,
+                headers,
+                body,
+                ..
+            } => {
+                let mut response = HttpResponse::build(*status);
+                for (name, value) in headers {
+                    response.append_header((name.clone(), value.clone()));
+                }
+                response.insert_header((IDEMPOTENT_REPLAYED, "true"));
+                Some(response.body(body.clone()))
+            }
+        }
+    }
+
+    fn finish(
+        &self,
+        key: &str,
+        fingerprint: u64,
+        response: Option<(StatusCode, HeaderMap, web::Bytes)>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        match response {
+            Some((status, headers, body)) => {
+                entries.insert(
+                    key.to_owned(),
+                    Entry::Done {
+                        fingerprint,
+                        stored_at: Instant::now(),
+                        status,
+                        headers,
+                        body,
+                    },
+                );
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+}
+
+/// Replay the stored response for a repeated `Idempotency-Key` instead of running the handler again.
+///
+/// Keys are scoped to the authenticated caller, so one caller never sees another's result.
+/// Server errors and `429`s are not stored, so a retry after one runs the handler again.
+pub async fn idempotency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(key) = req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned)
+    else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Some(store) = req.app_data::<web::Data<IdempotencyStore>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    // Anonymous requests, when authentication is off, all share one scope.
+    let caller = req
+        .extensions()
+        .get::<Principal>()
+        .map(|principal| principal.name.clone())
+        .unwrap_or_default();
+    let key = format!("{caller}\n{key}");
+
+    let payload = req.extract::<web::Bytes>().await?;
+    let mut hasher = DefaultHasher::new();
+    caller.hash(&mut hasher);
+    req.method().hash(&mut hasher);
+    req.path().hash(&mut hasher);
+    req.query_string().hash(&mut hasher);
+    payload.hash(&mut hasher);
+    let fingerprint = hasher.finish();
+    req.set_payload(payload.into());
+
+    if let Some(reply) = store.begin(&key, fingerprint) {
+        return Ok(req.into_response(reply));
+    }
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(err) => {
+            store.finish(&key, fingerprint, None);
+            return Err(err);
+        }
+    };
+    let status = response.status();
+    let headers = response.headers().clone();
+    let (request, response) = response.into_parts();
+    let body = match body::to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(err) => {
+            store.finish(&key, fingerprint, None);
+            return Err(actix_web::error::ErrorInternalServerError(err.into()));
+        }
+    };
+
+    let retryable = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+    let stored = (!retryable).then(|| (status, headers.clone(), body.clone()));
+    store.finish(&key, fingerprint, stored);
+
+    let mut reply = HttpResponse::build(status);
+    for (name, value) in &headers {
+        reply.append_header((name.clone(), value.clone()));
+    }
+    Ok(ServiceResponse::new(request, reply.body(body)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::auth::{authenticate, AuthConfig, Credential, Role};
+    use crate::controller::{move_robot, RobotState, DEFAULT_ROBOT};
+    use crate::idempotency::{idempotency, IdempotencyStore};
+    use crate::limits::{LimitConfig, Limits};
+
+    #[actix_web::test]
+    async fn test_retry_replays_first_result() {
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(web::Data::new(IdempotencyStore::new(Duration::from_secs(
+                    60,
+                ))))
+                .service(
+                    web::resource("/move_robot")
+                        .wrap(from_fn(idempotency))
+                        .route(web::post().to(move_robot)),
+                ),
+        )
+        .await;
+        let request = |instructions: &str| {
+            TestRequest::post()
+                .uri("/move_robot")
+                .insert_header(("idempotency-key", "retry-1"))
+                .set_json(serde_json::json!({ "instructions": instructions }))
+                .to_request()
+        };
+
+        let first = call_and_read_body(&app, request("A")).await;
+        let retry = call_service(&app, request("A")).await;
+        assert_eq!(retry.headers().get("idempotent-replayed").unwrap(), "true");
+        assert_eq!(actix_web::test::read_body(retry).await, first);
+        assert_eq!(robots.lock()[&DEFAULT_ROBOT].version, 2);
+
+        let reused = call_service(&app, request("AA")).await;
+        assert_eq!(reused.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn test_keys_are_scoped_to_the_caller() {
+        let credential = |name: &str, robots| Credential {
+            name: name.to_string(),
+            secret: name.to_string(),
+            role: Role::Operator,
+            robots,
+        };
+        let config = AuthConfig {
+            public_docs: true,
+            credentials: vec![credential("alice", vec![0]), credential("bob", vec![1])],
+        };
+        let limits = Limits::new(LimitConfig {
+            daily_instruction_quota: Some(1),
+            ..LimitConfig::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(limits))
+                .app_data(web::Data::new(IdempotencyStore::new(Duration::from_secs(
+                    60,
+                ))))
+                .wrap(from_fn(authenticate))
+                .service(
+                    web::resource("/move_robot")
+                        .wrap(from_fn(idempotency))
+                        .route(web::post().to(move_robot)),
+                ),
+        )
+        .await;
+        let request = |caller: &str, key: &str| {
+            TestRequest::post()
+                .uri("/move_robot")
+                .insert_header(("x-api-key", caller))
+                .insert_header(("idempotency-key", key))
+                .set_json(serde_json::json!({ "instructions": "A" }))
+                .to_request()
+        };
+
+        assert_eq!(
+            call_service(&app, request("alice", "k")).await.status(),
+            200
+        );
+        let resp = call_service(&app, request("bob", "k")).await;
+        assert_eq!(resp.status(), 403);
+        assert!(!resp.headers().contains_key("idempotent-replayed"));
+
+        let resp = call_service(&app, request("alice", "over-quota")).await;
+        assert_eq!(resp.status(), 429);
+        let retry = call_service(&app, request("alice", "over-quota")).await;
+        assert_eq!(retry.status(), 429);
+        assert!(!retry.headers().contains_key("idempotent-replayed"));
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{rt, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{authorize, Permission};
+use crate::battery::Batteries;
+use crate::controller::{
+    parse_program, precondition_failed, robot_not_found, ErrorResponse, RobotId, RobotModel,
+    RobotState, DEFAULT_ROBOT,
+};
+use crate::events::{Change, EventLog};
+use crate::limits::{check_program_size, Quota};
+use crate::metrics::{record_rejected, Metrics, Rejection};
+use crate::pose::Pose;
+use crate::simulation::Simulation;
+use crate::world::{World, WorldMap};
+
+pub type JobId = u64;
+
+/// Number of instructions applied per lock acquisition.
+pub const JOB_CHUNK_SIZE: usize = 1024;
+/// How long a finished job's status stays queryable.
+pub const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+/// Most finished jobs kept; the oldest are forgotten first.
+pub const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+impl JobState {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Cancelled | JobState::Failed
+        )
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct JobRequest {
+    /// Robot to run the program on, defaults to robot `0`.
+    #[schema(value_type = Option<u32>)]
+    pub robot_id: Option<RobotId>,
+    pub instructions: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JobStatus {
+    #[schema(value_type = u64)]
+    pub id: JobId,
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    pub state: JobState,
+    pub steps_done: usize,
+    pub total_steps: usize,
+    /// Pose of the robot at the time of the query.
+    pub pose: Option<RobotModel>,
+    pub error: Option<String>,
+}
+
+struct Job {
+    robot_id: RobotId,
+    state: JobState,
+    steps_done: usize,
+    total_steps: usize,
+    error: Option<String>,
+    finished_at: Option<Instant>,
+}
+
+impl Job {
+    fn set_state(&mut self, state: JobState) {
+        self.state = state;
+        if state.is_finished() && self.finished_at.is_none() {
+            self.finished_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Drop finished jobs older than [`FINISHED_JOB_RETENTION`], then the oldest beyond
+/// [`MAX_FINISHED_JOBS`].
+fn forget_finished(jobs: &mut HashMap<JobId, Job>) {
+    jobs.retain(|_, job| {
+        job.finished_at
+            .is_none_or(|at| at.elapsed() < FINISHED_JOB_RETENTION)
+    });
+    let mut finished: Vec<(Instant, JobId)> = jobs
+        .iter()
+        .filter_map(|(id, job)| Some((job.finished_at?, *id)))
+        .collect();
+    if finished.len() > MAX_FINISHED_JOBS {
+        finished.sort_unstable();
+        for (_, id) in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+            jobs.remove(id);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<JobId, Job>>,
+    closed: AtomicBool,
+}
+
+impl Jobs {
+    fn insert(&self, robot_id: RobotId, total_steps: usize) -> JobId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut jobs = self.jobs.lock().unwrap();
+        forget_finished(&mut jobs);
+        jobs.insert(
+            id,
+            Job {
+                robot_id,
+                state: JobState::Queued,
+                steps_done: 0,
+                total_steps,
+                error: None,
+                finished_at: None,
+            },
+        );
+        id
+    }
+
+    /// Record progress and tell the runner whether it should keep going.
+    fn advance(&self, id: JobId, steps_done: usize, state: JobState) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&id) else {
+            return false;
+        };
+        job.steps_done = steps_done;
+        if job.state == JobState::Cancelled {
+            return false;
+        }
+        job.set_state(state);
+        true
+    }
+
+    fn fail(&self, id: JobId, error: String) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.set_state(JobState::Failed);
+            job.error = Some(error);
+        }
+    }
+
+    fn robot(&self, id: JobId) -> Option<RobotId> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.robot_id)
+    }
+
+    fn cancel(&self, id: JobId) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            if !job.state.is_finished() {
+                job.set_state(JobState::Cancelled);
+            }
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| !job.state.is_finished())
+            .count()
+    }
+
+    /// Refuse new jobs and wait up to `timeout` for the running ones to finish.
+    ///
+    /// Jobs still running afterwards are cancelled; their count is returned.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.closed.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        while self.active() > 0 && started.elapsed() < timeout {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut cancelled = 0;
+        for job in jobs.values_mut().filter(|job| !job.state.is_finished()) {
+            job.set_state(JobState::Cancelled);
+            cancelled += 1;
+        }
+        cancelled
+    }
+
+    fn status(&self, id: JobId, robots: &RobotState) -> Option<JobStatus> {
+        let (robot_id, state, steps_done, total_steps, error) = {
+            let jobs = self.jobs.lock().unwrap();
+            let job = jobs.get(&id)?;
+            (
+                job.robot_id,
+                job.state,
+                job.steps_done,
+                job.total_steps,
+                job.error.clone(),
+            )
+        };
+        let pose = robots
+            .lock()
+            .get(&robot_id)
+            .map(|entry| entry.robot.clone());
+        Some(JobStatus {
+            id,
+            robot_id,
+            state,
+            steps_done,
+            total_steps,
+            pose,
+            error,
+        })
+    }
+}
+
+/// Optional server parts a job reports to, taken from the request that created it.
+struct JobContext {
+    metrics: Option<web::Data<Metrics>>,
+    events: Option<web::Data<EventLog>>,
+    simulation: Option<web::Data<Simulation>>,
+    batteries: Option<web::Data<Batteries>>,
+    world: Option<web::Data<World>>,
+    quota: Option<Quota>,
+}
+
+impl JobContext {
+    fn of(req: &HttpRequest) -> Self {
+        JobContext {
+            world: req.app_data::<web::Data<World>>().cloned(),
+            metrics: req.app_data::<web::Data<Metrics>>().cloned(),
+            events: req.app_data::<web::Data<EventLog>>().cloned(),
+            simulation: req.app_data::<web::Data<Simulation>>().cloned(),
+            batteries: req.app_data::<web::Data<Batteries>>().cloned(),
+            quota: Quota::of(req),
+        }
+    }
+}
+
+/// Apply the program chunk by chunk, releasing the robot lock in between.
+///
+/// In simulation mode the chunks are queued as motion instead.
+#[tracing::instrument(name = "job", skip(program, jobs, robots, context), fields(instructions = program.len()))]
+async fn run_job(
+    id: JobId,
+    robot_id: RobotId,
+    program: Vec<char>,
+    jobs: web::Data<Jobs>,
+    robots: web::Data<RobotState>,
+    context: JobContext,
+) {
+    let mut steps_done = 0;
+    if !jobs.advance(id, steps_done, JobState::Running) {
+        return;
+    }
+    for chunk in program.chunks(JOB_CHUNK_SIZE) {
+        {
+            let mut robots = robots.lock();
+            let Some(entry) = robots.get_mut(&robot_id) else {
+                drop(robots);
+                jobs.fail(id, format!("robot {robot_id} not found"));
+                tracing::warn!(steps_done, "robot disappeared, job failed");
+                return;
+            };
+            let queued = context
+                .simulation
+                .as_ref()
+                .map(|simulation| simulation.queued(robot_id))
+                .unwrap_or_default();
+            let start = queued
+                .iter()
+                .fold(Pose::from(&entry.robot), |pose, &movement| {
+                    pose.step(movement)
+                });
+            let blocked = match &context.world {
+                Some(world) => world.map().collision(start, chunk),
+                None => WorldMap::default().collision(start, chunk),
+            };
+            let clear = &chunk[..blocked.map_or(chunk.len(), |blocked| blocked.steps)];
+            let plan = context.batteries.as_ref().map(|batteries| {
+                batteries.plan(robot_id, Pose::from(&entry.robot), &queued, clear)
+            });
+            let runnable = &clear[..plan.as_ref().map_or(clear.len(), |plan| plan.steps)];
+            let charged = context.quota.as_ref().map_or(Ok(()), |quota| {
+                quota.charge(steps_done + runnable.len(), runnable.len())
+            });
+            if let Err(refusal) = charged {
+                drop(robots);
+                if let Some(metrics) = &context.metrics {
+                    metrics.rejected(Rejection::Limit, runnable.len());
+                }
+                jobs.fail(id, refusal.to_string());
+                tracing::info!(steps_done, %refusal, "limit reached, job failed");
+                return;
+            }
+            if let Some(simulation) = &context.simulation {
+                simulation.enqueue(robot_id, runnable);
+            } else if !runnable.is_empty() {
+                if let Some(events) = &context.events {
+                    let change = Change::Moved {
+                        instructions: runnable.iter().collect(),
+                    };
+                    let battery = plan.as_ref().map(|plan| plan.level);
+                    if let Err(err) = events.append(robot_id, change, battery) {
+                        drop(robots);
+                        jobs.fail(id, format!("could not record event: {err}"));
+                        tracing::error!(%err, steps_done, "event log write failed, job failed");
+                        return;
+                    }
+                }
+                let robot = entry.update();
+                for &movement in runnable {
+                    robot.execute(movement);
+                }
+                if let (Some(batteries), Some(plan)) = (&context.batteries, &plan) {
+                    batteries.commit(robot_id, plan);
+                }
+                if let Some(metrics) = &context.metrics {
+                    metrics.executed(runnable.len());
+                }
+            }
+            steps_done += runnable.len();
+            if runnable.len() < clear.len() {
+                drop(robots);
+                jobs.advance(id, steps_done, JobState::Running);
+                jobs.fail(
+                    id,
+                    format!("battery ran out after {steps_done} instructions"),
+                );
+                tracing::info!(steps_done, "battery ran out, job failed");
+                return;
+            }
+            if let Some(blocked) = blocked {
+                drop(robots);
+                jobs.advance(id, steps_done, JobState::Running);
+                jobs.fail(
+                    id,
+                    format!(
+                        "collision with {} at {},{} after {steps_done} instructions",
+                        blocked.reading.name(),
+                        blocked.cell.x,
+                        blocked.cell.y
+                    ),
+                );
+                tracing::info!(steps_done, %blocked, "collision ahead, job failed");
+                return;
+            }
+        }
+        if !jobs.advance(id, steps_done, JobState::Running) {
+            tracing::info!(steps_done, "job cancelled");
+            return;
+        }
+        tokio::task::yield_now().await;
+    }
+    jobs.advance(id, steps_done, JobState::Completed);
+    let pose = robots
+        .lock()
+        .get(&robot_id)
+        .map(|entry| entry.robot.clone());
+    tracing::info!(instructions = steps_done, ?pose, "job completed");
+}
+
+/// Enqueue a program to run in the background and return its job id.
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "Job accepted", body = JobStatus),
+        (status = 400, description = "Invalid program", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse),
+        (status = 413, description = "Too many instructions for one request", body = ErrorResponse),
+        (status = 429, description = "Daily instruction quota exceeded", body = ErrorResponse),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 503, description = "Server is shutting down", body = ErrorResponse)
+    )
+)]
+pub async fn create_job(
+    jobs: web::Data<Jobs>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    req: web::Json<JobRequest>,
+) -> impl Responder {
+    let robot_id = req.robot_id.unwrap_or(DEFAULT_ROBOT);
+    if let Some(response) = authorize(&http, Permission::Move, robot_id) {
+        return response;
+    }
+    if jobs.closed.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable()
+            .json(ErrorResponse::new("server is shutting down, no new jobs"));
+    }
+    let program = match parse_program(&req.instructions) {
+        Ok(program) => program,
+        Err(err) => {
+            record_rejected(&http, Rejection::Invalid, 1);
+            return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+        }
+    };
+    {
+        let robots = robots.lock();
+        let Some(entry) = robots.get(&robot_id) else {
+            return robot_not_found(robot_id);
+        };
+        if let Some(response) = precondition_failed(&http, Some(entry)) {
+            return response;
+        }
+    }
+    // Each chunk is charged to the daily quota as it is applied.
+    if let Some(response) = check_program_size(&http, program.len()) {
+        return response;
+    }
+
+    let id = jobs.insert(robot_id, program.len());
+    tracing::info!(
+        job_id = id,
+        robot_id,
+        instructions = program.len(),
+        "job queued"
+    );
+    rt::spawn(run_job(
+        id,
+        robot_id,
+        program,
+        jobs.clone(),
+        robots.clone(),
+        JobContext::of(&http),
+    ));
+
+    HttpResponse::Accepted().json(jobs.status(id, &robots))
+}
+
+/// Get the progress of a job.
+///
+/// Finished jobs are forgotten after an hour, or sooner once more than 1000 have finished.
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "Job progress", body = JobStatus),
+        (status = 404, description = "Unknown or forgotten job", body = ErrorResponse)
+    )
+)]
+pub async fn job_status(
+    jobs: web::Data<Jobs>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    path: web::Path<JobId>,
+) -> impl Responder {
+    let Some(status) = jobs.status(*path, &robots) else {
+        return job_not_found(*path);
+    };
+    if let Some(response) = authorize(&http, Permission::Read, status.robot_id) {
+        return response;
+    }
+    HttpResponse::Ok().json(status)
+}
+
+/// Cancel a queued or running job. Steps already applied are kept.
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "Job cancelled", body = JobStatus),
+        (status = 404, description = "Unknown job", body = ErrorResponse)
+    )
+)]
+pub async fn cancel_job(
+    jobs: web::Data<Jobs>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    path: web::Path<JobId>,
+) -> impl Responder {
+    let Some(robot_id) = jobs.robot(*path) else {
+        return job_not_found(*path);
+    };
+    if let Some(response) = authorize(&http, Permission::Move, robot_id) {
+        return response;
+    }
+    jobs.cancel(*path);
+    HttpResponse::Ok().json(jobs.status(*path, &robots))
+}
+
+fn job_not_found(id: JobId) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(format!("job {id} not found")))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::{test, web, App};
+
+    use crate::controller::RobotState;
+    use crate::events::{Change, EventLog};
+    use crate::jobs::{
+        cancel_job, create_job, job_status, JobState, Jobs, JOB_CHUNK_SIZE, MAX_FINISHED_JOBS,
+    };
+    use crate::limits::{limit_usage, LimitConfig, Limits};
+    use crate::pose::Pose;
+    use crate::storage::TempStorage;
+
+    #[actix_web::test]
+    async fn test_finished_jobs_are_forgotten() {
+        let jobs = Jobs::default();
+        let running = jobs.insert(0, 1);
+        for _ in 0..MAX_FINISHED_JOBS + 10 {
+            let id = jobs.insert(0, 1);
+            jobs.fail(id, "failed".to_string());
+        }
+        let last = jobs.insert(0, 1);
+
+        assert_eq!(jobs.jobs.lock().unwrap().len(), MAX_FINISHED_JOBS + 2);
+        assert_eq!(jobs.robot(running), Some(0));
+        assert_eq!(jobs.robot(running + 1), None);
+        assert_eq!(jobs.robot(last - 1), Some(0));
+    }
+
+    #[actix_web::test]
+    async fn test_job_runs_to_completion() {
+        let jobs = web::Data::new(Jobs::default());
+        let robots = web::Data::new(RobotState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(jobs.clone())
+                .app_data(robots.clone())
+                .route("/jobs", web::post().to(create_job))
+                .route("/jobs/{id}", web::get().to(job_status))
+                .route("/jobs/{id}", web::delete().to(cancel_job)),
+        )
+        .await;
+
+        let program = "RL".repeat(JOB_CHUNK_SIZE * 2) + "RAALAL";
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .set_json(serde_json::json!({ "instructions": program }))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["total_steps"], program.len());
+
+        let id = resp["id"].as_u64().unwrap();
+        loop {
+            let req = test::TestRequest::get()
+                .uri(&format!("/jobs/{id}"))
+                .to_request();
+            let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            if resp["state"] == serde_json::json!(JobState::Completed) {
+                assert_eq!(resp["steps_done"], program.len());
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .set_json(serde_json::json!({ "instructions": "RAX" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_stops_a_running_job() {
+        let storage = TempStorage::new("jobs");
+        let log = web::Data::new(EventLog::open(&storage).unwrap());
+        let jobs = web::Data::new(Jobs::default());
+        let robots = web::Data::new(RobotState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(jobs.clone())
+                .app_data(robots.clone())
+                .app_data(log.clone())
+                .route("/jobs", web::post().to(create_job))
+                .route("/jobs/{id}", web::delete().to(cancel_job)),
+        )
+        .await;
+
+        let total = JOB_CHUNK_SIZE * 64;
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .set_json(serde_json::json!({ "instructions": "A".repeat(total) }))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = resp["id"].as_u64().unwrap();
+        while jobs.status(id, &robots).unwrap().steps_done < JOB_CHUNK_SIZE * 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/jobs/{id}"))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["state"], serde_json::json!(JobState::Cancelled));
+        // Let the runner notice the cancellation.
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        let status = jobs.status(id, &robots).unwrap();
+        assert_eq!(status.state, JobState::Cancelled);
+        assert!(status.steps_done >= JOB_CHUNK_SIZE * 2 && status.steps_done < total);
+        assert_eq!(status.steps_done % JOB_CHUNK_SIZE, 0);
+        let pose = Pose::from(&robots.lock()[&0].robot);
+        assert_eq!(pose.y as usize, status.steps_done);
+        let logged: usize = log
+            .events(Some(0), 0)
+            .unwrap()
+            .iter()
+            .map(|event| match &event.change {
+                Change::Moved { instructions } => instructions.len(),
+                change => panic!("unexpected {change:?}"),
+            })
+            .sum();
+        assert_eq!(logged, status.steps_done);
+    }
+
+    #[actix_web::test]
+    async fn test_drain_cancels_unfinished_jobs() {
+        let jobs = web::Data::new(Jobs::default());
+        let robots = web::Data::new(RobotState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(jobs.clone())
+                .app_data(robots.clone())
+                .route("/jobs", web::post().to(create_job)),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::post()
+                .uri("/jobs")
+                .set_json(serde_json::json!({ "instructions": "A".repeat(JOB_CHUNK_SIZE * 64) }))
+                .to_request()
+        };
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, request()).await;
+        let id = resp["id"].as_u64().unwrap();
+        assert_eq!(jobs.drain(Duration::ZERO).await, 1);
+        assert_eq!(jobs.status(id, &robots).unwrap().state, JobState::Cancelled);
+
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_jobs_charge_the_chunks_they_apply() {
+        let limits = Limits::new(LimitConfig {
+            burst: 1,
+            max_instructions_per_request: Some(JOB_CHUNK_SIZE * 4),
+            daily_instruction_quota: Some(JOB_CHUNK_SIZE as u64 * 3 / 2),
+            ..LimitConfig::default()
+        });
+        let jobs = web::Data::new(Jobs::default());
+        let robots = web::Data::new(RobotState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(jobs.clone())
+                .app_data(robots.clone())
+                .app_data(web::Data::new(limits))
+                .route("/jobs", web::post().to(create_job))
+                .route("/limits", web::get().to(limit_usage)),
+        )
+        .await;
+        let request = |instructions: String| {
+            test::TestRequest::post()
+                .uri("/jobs")
+                .set_json(serde_json::json!({ "instructions": instructions }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("L".repeat(JOB_CHUNK_SIZE * 4 + 1))).await;
+        assert_eq!(resp.status(), 413);
+
+        let resp: serde_json::Value =
+            test::call_and_read_body_json(&app, request("L".repeat(JOB_CHUNK_SIZE * 2))).await;
+        let id = resp["id"].as_u64().unwrap();
+        let status = loop {
+            let status = jobs.status(id, &robots).unwrap();
+            if status.state != JobState::Queued && status.state != JobState::Running {
+                break status;
+            }
+            tokio::task::yield_now().await;
+        };
+        // The first chunk fits the quota, the second does not and is neither applied nor charged.
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.steps_done, JOB_CHUNK_SIZE);
+        assert_eq!(
+            status.error.unwrap(),
+            format!(
+                "daily instruction quota of {} exceeded, {JOB_CHUNK_SIZE} used today",
+                JOB_CHUNK_SIZE * 3 / 2
+            )
+        );
+        let req = test::TestRequest::get().uri("/limits").to_request();
+        let usage: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(usage["instructions_today"], JOB_CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,24 @@
+//! Robot simulator: the engines, the world model and the REST handlers served by `main.rs`.
+
+pub mod auth;
+pub mod battery;
+pub mod checkpoints;
+pub mod controller;
+pub mod events;
+pub mod health;
+pub mod history;
+pub mod idempotency;
+pub mod jobs;
+pub mod limits;
+pub mod metrics;
+pub mod pose;
+pub mod render;
+pub mod rhai_scripts;
+pub mod script;
+pub mod sensors;
+pub mod shutdown;
+pub mod simulation;
+pub mod solutions;
+pub mod storage;
+pub mod telemetry;
+pub mod world;
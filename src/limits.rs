@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::Principal;
+use crate::controller::ErrorResponse;
+use crate::metrics::{record_rejected, Rejection};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Limits applied to every client. `None` leaves that limit off.
+#[derive(Clone, Default)]
+pub struct LimitConfig {
+    /// Requests per second refilled into each client's bucket.
+    pub requests_per_second: Option<f64>,
+    /// Bucket size, i.e. how many requests may arrive at once.
+    pub burst: u32,
+    pub max_instructions_per_request: Option<usize>,
+    pub daily_instruction_quota: Option<u64>,
+}
+
+impl LimitConfig {
+    /// Read `ROBOT_RATE_LIMIT_PER_SEC`, `ROBOT_RATE_LIMIT_BURST`,
+    /// `ROBOT_MAX_INSTRUCTIONS` and `ROBOT_DAILY_INSTRUCTION_QUOTA`.
+    ///
+    /// A value that is not a number, or that [`LimitConfig::validate`] rejects, is an error.
+    pub fn from_env() -> io::Result<Self> {
+        fn var<T: std::str::FromStr>(name: &str) -> io::Result<Option<T>> {
+            let Ok(value) = std::env::var(name) else {
+                return Ok(None);
+            };
+            value.parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} is not a valid number: {value}"),
+                )
+            })
+        }
+        let requests_per_second = var("ROBOT_RATE_LIMIT_PER_SEC")?;
+        let config = LimitConfig {
+            requests_per_second,
+            burst: var("ROBOT_RATE_LIMIT_BURST")?
+                .unwrap_or_else(|| requests_per_second.map_or(1, |rate: f64| rate.ceil() as u32)),
+            max_instructions_per_request: var("ROBOT_MAX_INSTRUCTIONS")?,
+            daily_instruction_quota: var("ROBOT_DAILY_INSTRUCTION_QUOTA")?,
+        };
+        config
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(config)
+    }
+
+    /// Check the bucket refills and holds at least one request.
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .requests_per_second
+            .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+        {
+            return Err("ROBOT_RATE_LIMIT_PER_SEC must be a number above 0".to_string());
+        }
+        if self.burst == 0 {
+            return Err("ROBOT_RATE_LIMIT_BURST must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+struct ClientUsage {
+    tokens: f64,
+    refilled_at: Instant,
+    day: u64,
+    instructions_today: u64,
+}
+
+struct Clients {
+    usage: HashMap<String, ClientUsage>,
+    /// The day clients were last pruned.
+    pruned_on: u64,
+}
+
+pub struct Limits {
+    config: LimitConfig,
+    clients: Mutex<Clients>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Usage {
+    pub client: String,
+    pub requests_per_second: Option<f64>,
+    pub burst: u32,
+    pub tokens_remaining: Option<f64>,
+    pub max_instructions_per_request: Option<usize>,
+    pub daily_instruction_quota: Option<u64>,
+    pub instructions_today: u64,
+}
+
+fn today() -> (u64, Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let until_tomorrow = SECONDS_PER_DAY - now % SECONDS_PER_DAY;
+    (now / SECONDS_PER_DAY, Duration::from_secs(until_tomorrow))
+}
+
+fn too_many_requests(retry_after: Duration, error: String) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1)))
+        .json(ErrorResponse::new(error))
+}
+
+impl Limits {
+    pub fn new(config: LimitConfig) -> Self {
+        Limits {
+            config,
+            clients: Mutex::new(Clients {
+                usage: HashMap::new(),
+                pruned_on: today().0,
+            }),
+        }
+    }
+
+    /// Tokens in the bucket now, counting what has refilled since it was last used.
+    fn tokens(&self, usage: &ClientUsage) -> f64 {
+        let Some(rate) = self.config.requests_per_second else {
+            return f64::from(self.config.burst);
+        };
+        let elapsed = usage.refilled_at.elapsed().as_secs_f64();
+        (usage.tokens + elapsed * rate).min(f64::from(self.config.burst))
+    }
+
+    fn with_usage<T>(&self, client: &str, f: impl FnOnce(&mut ClientUsage) -> T) -> T {
+        let mut clients = self.clients.lock().unwrap();
+        let day = today().0;
+        if clients.pruned_on != day {
+            // Clients not seen today whose bucket has refilled would start over the same.
+            let burst = f64::from(self.config.burst);
+            clients
+                .usage
+                .retain(|_, usage| usage.day == day || self.tokens(usage) < burst);
+            clients.pruned_on = day;
+        }
+        let usage = clients
+            .usage
+            .entry(client.to_owned())
+            .or_insert_with(|| ClientUsage {
+                tokens: f64::from(self.config.burst),
+                refilled_at: Instant::now(),
+                day,
+                instructions_today: 0,
+            });
+
+        if self.config.requests_per_second.is_some() {
+            usage.tokens = self.tokens(usage);
+            usage.refilled_at = Instant::now();
+        }
+        if usage.day != day {
+            usage.day = day;
+            usage.instructions_today = 0;
+        }
+        f(usage)
+    }
+
+    /// Take one token from the client's bucket, or tell it how long to wait.
+    fn take_request(&self, client: &str) -> Result<(), Duration> {
+        self.request_slot(client, true)
+    }
+
+    /// Like [`Limits::take_request`], but leave the token in the bucket.
+    fn check_request(&self, client: &str) -> Result<(), Duration> {
+        self.request_slot(client, false)
+    }
+
+    fn request_slot(&self, client: &str, take: bool) -> Result<(), Duration> {
+        let Some(rate) = self.config.requests_per_second else {
+            return Ok(());
+        };
+        self.with_usage(client, |usage| {
+            if usage.tokens >= 1.0 {
+                if take {
+                    usage.tokens -= 1.0;
+                }
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - usage.tokens) / rate)
+                    .max(Duration::from_secs(1)))
+            }
+        })
+    }
+
+    fn charge(&self, client: &str, request_total: usize, added: usize) -> Result<(), Refusal> {
+        if let Some(max) = self.config.max_instructions_per_request {
+            if request_total > max {
+                return Err(Refusal::TooLarge { max });
+            }
+        }
+        let Some(quota) = self.config.daily_instruction_quota else {
+            return Ok(());
+        };
+        self.with_usage(client, |usage| {
+            let instructions = added as u64;
+            if usage.instructions_today + instructions > quota {
+                return Err(Refusal::QuotaExceeded {
+                    quota,
+                    used: usage.instructions_today,
+                });
+            }
+            usage.instructions_today += instructions;
+            Ok(())
+        })
+    }
+
+    fn usage(&self, client: &str) -> Usage {
+        self.with_usage(client, |usage| Usage {
+            client: client.to_owned(),
+            requests_per_second: self.config.requests_per_second,
+            burst: self.config.burst,
+            tokens_remaining: self.config.requests_per_second.map(|_| usage.tokens),
+            max_instructions_per_request: self.config.max_instructions_per_request,
+            daily_instruction_quota: self.config.daily_instruction_quota,
+            instructions_today: usage.instructions_today,
+        })
+    }
+}
+
+/// Instructions a limit refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refusal {
+    /// More instructions in one request than `max`.
+    TooLarge { max: usize },
+    /// The caller has used `used` of its daily `quota`.
+    QuotaExceeded { quota: u64, used: u64 },
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::TooLarge { max } => write!(
+                f,
+                "program has more than {max} instructions, the limit per request"
+            ),
+            Refusal::QuotaExceeded { quota, used } => write!(
+                f,
+                "daily instruction quota of {quota} exceeded, {used} used today"
+            ),
+        }
+    }
+}
+
+impl Refusal {
+    fn response(&self) -> HttpResponse {
+        match self {
+            Refusal::TooLarge { .. } => {
+                HttpResponse::PayloadTooLarge().json(ErrorResponse::new(self.to_string()))
+            }
+            Refusal::QuotaExceeded { .. } => too_many_requests(today().1, self.to_string()),
+        }
+    }
+}
+
+/// Authenticated clients are limited per credential, anonymous ones per IP address.
+fn client_key(req: &HttpRequest) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return format!("key:{}", principal.name);
+    }
+    ip_key(req)
+}
+
+fn ip_key(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+/// Count `added` instructions against the caller's daily quota, with `request_total`
+/// executed by this request so far checked against the per-request cap.
+///
+/// Over the cap answers `413`, over the quota `429` with `Retry-After` set to the next UTC midnight.
+pub fn charge_instructions(
+    req: &HttpRequest,
+    request_total: usize,
+    added: usize,
+) -> Option<HttpResponse> {
+    let limits = req.app_data::<web::Data<Limits>>()?;
+    let refusal = limits
+        .charge(&client_key(req), request_total, added)
+        .err()?;
+    record_rejected(req, Rejection::Limit, added);
+    Some(refusal.response())
+}
+
+/// Check a program of `request_total` instructions against the per-request cap only,
+/// for work whose instructions are charged as they are applied.
+pub fn check_program_size(req: &HttpRequest, request_total: usize) -> Option<HttpResponse> {
+    let limits = req.app_data::<web::Data<Limits>>()?;
+    let refusal = limits.charge(&client_key(req), request_total, 0).err()?;
+    record_rejected(req, Rejection::Limit, request_total);
+    Some(refusal.response())
+}
+
+/// The caller's limits, kept for work that outlives the request, such as a job.
+pub struct Quota {
+    limits: web::Data<Limits>,
+    client: String,
+}
+
+impl Quota {
+    /// The limits of the caller of `req`, when limits are registered.
+    pub fn of(req: &HttpRequest) -> Option<Self> {
+        Some(Quota {
+            limits: req.app_data::<web::Data<Limits>>()?.clone(),
+            client: client_key(req),
+        })
+    }
+
+    /// Like [`charge_instructions`], for instructions about to be applied.
+    pub fn charge(&self, request_total: usize, added: usize) -> Result<(), Refusal> {
+        self.limits.charge(&self.client, request_total, added)
+    }
+}
+
+/// Token-bucket rate limiting per client, answering `429` with `Retry-After`.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    if let Some(limits) = req.app_data::<web::Data<Limits>>() {
+        let client = client_key(req.request());
+        if let Err(retry_after) = limits.take_request(&client) {
+            let response = too_many_requests(retry_after, "rate limit exceeded".to_string());
+            return Ok(req.into_response(response));
+        }
+    }
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// Throttle failed authentication per IP address, so credentials cannot be guessed quickly.
+///
+/// Wrap it around `authenticate`: every `401` or `403` without a valid credential takes a
+/// token from the address's bucket, and while that bucket is empty the address gets `429` before credentials are checked.
+pub async fn rate_limit_by_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limits) = req.app_data::<web::Data<Limits>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let ip = ip_key(req.request());
+    if let Err(retry_after) = limits.check_request(&ip) {
+        let response = too_many_requests(retry_after, "rate limit exceeded".to_string());
+        return Ok(req.into_response(response));
+    }
+    let response = next.call(req).await?;
+    let authenticated = response.request().extensions().contains::<Principal>();
+    if !authenticated
+        && matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )
+    {
+        let _ = limits.take_request(&ip);
+    }
+    Ok(response.map_into_boxed_body())
+}
+
+/// Current limits and usage of the calling client.
+#[utoipa::path(
+    get,
+    path = "/limits",
+    responses(
+        (status = 200, description = "Limits and usage of the caller", body = Usage)
+    )
+)]
+pub async fn limit_usage(limits: web::Data<Limits>, http: HttpRequest) -> impl Responder {
+    HttpResponse::Ok().json(limits.usage(&client_key(&http)))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::auth::{authenticate, AuthConfig, Credential, Role};
+    use crate::controller::{move_robot, robot_position, RobotState};
+    use crate::limits::{rate_limit, rate_limit_by_ip, ClientUsage, LimitConfig, Limits};
+
+    #[actix_web::test]
+    async fn test_limits() {
+        let limits = Limits::new(LimitConfig {
+            requests_per_second: Some(1000.0),
+            burst: 1000,
+            max_instructions_per_request: Some(4),
+            daily_instruction_quota: Some(6),
+        });
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(limits))
+                .wrap(from_fn(rate_limit))
+                .route("/move_robot", web::post().to(move_robot)),
+        )
+        .await;
+        let request = |uri: &str, body: serde_json::Value| {
+            TestRequest::post().uri(uri).set_json(body).to_request()
+        };
+        let moves = |instructions: &str| {
+            request(
+                "/move_robot",
+                serde_json::json!({ "instructions": instructions }),
+            )
+        };
+
+        assert_eq!(call_service(&app, moves("AAAAA")).await.status(), 413);
+        assert_eq!(call_service(&app, moves("AAAA")).await.status(), 200);
+        // Requests that do not move the robot leave the quota alone.
+        let unknown = request(
+            "/move_robot?robot_id=9",
+            serde_json::json!({ "instructions": "AA" }),
+        );
+        assert_eq!(call_service(&app, unknown).await.status(), 404);
+        let dry_run = serde_json::json!({ "instructions": "AA", "dry_run": true });
+        let resp = call_service(&app, request("/move_robot", dry_run)).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(call_service(&app, moves("AA")).await.status(), 200);
+
+        let resp = call_service(&app, moves("A")).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("retry-after"));
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(
+            body["error"],
+            "daily instruction quota of 6 exceeded, 6 used today"
+        );
+    }
+
+    #[test]
+    fn test_config_rejects_rates_that_never_refill() {
+        let config = LimitConfig {
+            requests_per_second: Some(0.5),
+            burst: 1,
+            ..LimitConfig::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let config = LimitConfig {
+                requests_per_second: Some(rate),
+                ..config.clone()
+            };
+            assert!(config.validate().is_err(), "{rate}");
+        }
+        let empty_bucket = LimitConfig { burst: 0, ..config };
+        assert!(empty_bucket.validate().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit() {
+        let limits = Limits::new(LimitConfig {
+            requests_per_second: Some(0.001),
+            burst: 2,
+            ..LimitConfig::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(limits))
+                .wrap(from_fn(rate_limit))
+                .route("/move_robot", web::post().to(move_robot)),
+        )
+        .await;
+        let request = || {
+            TestRequest::post()
+                .uri("/move_robot")
+                .set_json(serde_json::json!({ "instructions": "A" }))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, request()).await.status(), 200);
+        assert_eq!(call_service(&app, request()).await.status(), 200);
+        let resp = call_service(&app, request()).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("retry-after"));
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["error"], "rate limit exceeded");
+    }
+
+    #[actix_web::test]
+    async fn test_failed_authentication_is_throttled() {
+        let limits = Limits::new(LimitConfig {
+            requests_per_second: Some(0.001),
+            burst: 2,
+            ..LimitConfig::default()
+        });
+        let config = AuthConfig {
+            public_docs: true,
+            credentials: vec![Credential {
+                name: "ci".to_string(),
+                secret: "s3cret".to_string(),
+                role: Role::Viewer,
+                robots: Vec::new(),
+            }],
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(limits))
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(authenticate))
+                .wrap(from_fn(rate_limit_by_ip))
+                .route("/robot_position", web::get().to(robot_position)),
+        )
+        .await;
+        let request = |key: &str| {
+            TestRequest::get()
+                .uri("/robot_position")
+                .insert_header(("x-api-key", key))
+                .to_request()
+        };
+
+        // Valid credentials do not use up the address's bucket.
+        for _ in 0..2 {
+            assert_eq!(call_service(&app, request("s3cret")).await.status(), 200);
+        }
+        assert_eq!(call_service(&app, request("guess-1")).await.status(), 403);
+        assert_eq!(call_service(&app, request("guess-2")).await.status(), 403);
+        let resp = call_service(&app, request("s3cret")).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("retry-after"));
+    }
+
+    #[test]
+    fn test_idle_clients_are_pruned_the_next_day() {
+        let limits = Limits::new(LimitConfig {
+            requests_per_second: Some(1.0),
+            burst: 2,
+            ..LimitConfig::default()
+        });
+        {
+            let mut clients = limits.clients.lock().unwrap();
+            clients.pruned_on -= 1;
+            for (client, tokens) in [("ip:idle", 2.0), ("ip:busy", 0.0)] {
+                let usage = ClientUsage {
+                    tokens,
+                    refilled_at: std::time::Instant::now(),
+                    day: clients.pruned_on,
+                    instructions_today: 1,
+                };
+                clients.usage.insert(client.to_string(), usage);
+            }
+        }
+        limits.take_request("ip:new").unwrap();
+
+        let clients = limits.clients.lock().unwrap();
+        let mut kept: Vec<&str> = clients.usage.keys().map(String::as_str).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, ["ip:busy", "ip:new"]);
+    }
+}
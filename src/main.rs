@@ -1,49 +1,219 @@
-use crate::controller::{move_robot, reposition_robot, reset_robot, robot_position, ApiDoc};
-use actix_web::{web, App, HttpServer};
-use controller::RobotState;
-use solutions::*;
+use actix_web::middleware::from_fn;
+use actix_web::{rt, web, App, HttpServer};
+use robot::auth::{authenticate, AuthConfig};
+use robot::battery::{Batteries, BatteryConfig};
+use robot::checkpoints::{
+    delete_checkpoint, get_checkpoint, list_checkpoints, restore_checkpoint, save_checkpoint,
+    Checkpoints,
+};
+use robot::controller::{
+    move_robot, move_robot_stream, reposition_robot, reset_robot, robot_position, ApiDoc,
+};
+use robot::events::{list_events, verify_events, EventLog};
+use robot::health::{healthz, readyz, version};
+use robot::history::{history, position_at};
+use robot::idempotency::{idempotency, IdempotencyStore};
+use robot::jobs::{cancel_job, create_job, job_status, Jobs};
+use robot::limits::{limit_usage, rate_limit, rate_limit_by_ip, LimitConfig, Limits};
+use robot::metrics::{metrics, track_requests, Metrics};
+use robot::render::{render_svg, render_text};
+use robot::rhai_scripts::{
+    delete_rhai_script, get_rhai_script, list_rhai_scripts, run_rhai_script, save_rhai_script,
+    RhaiScripts,
+};
+use robot::script::run_script;
+use robot::sensors::robot_sensors;
+use robot::simulation::{
+    clock_status, pause_clock, resume_clock, set_speed, step_clock, Simulation, SimulationConfig,
+    SystemClock,
+};
+use robot::storage::Storage;
+use robot::telemetry::request_id;
+use robot::world::{get_world, put_world, World};
+use robot::{events, shutdown, simulation, solutions, telemetry};
+use std::process::ExitCode;
+use std::sync::Arc;
 use utoipa::OpenApi;
 
-use std::sync::Mutex;
 use utoipa_swagger_ui::SwaggerUi;
 
-mod controller;
-mod solutions;
-
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    #[cfg(not(feature = "type_state"))]
-    let robot_state = web::Data::new(RobotState(Mutex::new(Robot::new(0, 0, Direction::North))));
+async fn main() -> std::io::Result<ExitCode> {
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        events::print_replay(&Storage::from_env())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    telemetry::init();
 
-    #[cfg(feature = "type_state")]
-    let robot_state = web::Data::new(RobotState(Mutex::new(RobotWithFace::North(Robot::new(
-        0, 0,
-    )))));
+    let storage = web::Data::new(Storage::from_env());
+    let event_log = EventLog::open(&storage)?;
+    let robot_state = events::recover(&event_log);
+    let robot_state = web::Data::new(robot_state);
+    let event_log = web::Data::new(event_log);
+    let checkpoints = web::Data::new(Checkpoints::load(Storage::clone(&storage))?);
+    let world = web::Data::new(World::load(Storage::clone(&storage))?);
+    let rhai_scripts = web::Data::new(RhaiScripts::load(Storage::clone(&storage))?);
+    let jobs = web::Data::new(Jobs::default());
+    let idempotency_store = web::Data::new(IdempotencyStore::from_env());
+    let limits = web::Data::new(Limits::new(LimitConfig::from_env()?));
+    let server_metrics = web::Data::new(Metrics::default());
+    let auth_config = AuthConfig::from_env()?.map(web::Data::new);
+    if auth_config.is_none() {
+        tracing::warn!("ROBOT_AUTH_FILE is not set, authentication is disabled");
+    }
+
+    let batteries = BatteryConfig::from_env().map(|config| {
+        web::Data::new(Batteries::new(
+            config,
+            world.clone(),
+            Arc::new(SystemClock::new()),
+        ))
+    });
+    if batteries.is_some() {
+        tracing::info!("batteries on, instructions use energy");
+    }
+
+    let simulation = SimulationConfig::from_env()?
+        .map(|config| web::Data::new(Simulation::new(config, Arc::new(SystemClock::new()))));
+    if let Some(simulation) = &simulation {
+        tracing::info!("simulation mode on, instructions take simulated time");
+        rt::spawn(simulation::run(
+            simulation.clone(),
+            robot_state.clone(),
+            Some(event_log.clone()),
+            batteries.clone(),
+            Some(server_metrics.clone()),
+        ));
+    }
 
     solutions::run();
 
-    println!("Starting server at http://127.0.0.1:8080");
+    tracing::info!("Starting server at http://127.0.0.1:8080");
 
-    HttpServer::new(move || {
-        App::new()
+    let shutdown_timeout = shutdown::timeout_from_env();
+    let (final_robots, final_log, draining_jobs) =
+        (robot_state.clone(), event_log.clone(), jobs.clone());
+    let (final_simulation, final_batteries, final_metrics) = (
+        simulation.clone(),
+        batteries.clone(),
+        server_metrics.clone(),
+    );
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
             .app_data(robot_state.clone())
+            .app_data(jobs.clone())
+            .app_data(idempotency_store.clone())
+            .app_data(limits.clone())
+            .app_data(server_metrics.clone())
+            .app_data(storage.clone())
+            .app_data(event_log.clone())
+            .app_data(checkpoints.clone())
+            .app_data(world.clone())
+            .app_data(rhai_scripts.clone());
+        if let Some(auth_config) = &auth_config {
+            app = app.app_data(auth_config.clone());
+        }
+        if let Some(simulation) = &simulation {
+            app = app.app_data(simulation.clone());
+        }
+        if let Some(batteries) = &batteries {
+            app = app.app_data(batteries.clone());
+        }
+        app.wrap(from_fn(rate_limit))
+            .wrap(from_fn(authenticate))
+            .wrap(from_fn(rate_limit_by_ip))
+            .wrap(from_fn(track_requests))
+            .wrap(from_fn(request_id))
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-docs/openapi.json", ApiDoc::openapi()),
             )
-            .route("/move_robot", web::post().to(move_robot))
-            .route("/reposition_robot", web::post().to(reposition_robot))
-            .route("/reset_robot", web::post().to(reset_robot))
+            .service(
+                web::resource("/move_robot")
+                    .wrap(from_fn(idempotency))
+                    .route(web::post().to(move_robot)),
+            )
+            .route("/move_robot/stream", web::post().to(move_robot_stream))
+            .service(
+                web::resource("/reposition_robot")
+                    .wrap(from_fn(idempotency))
+                    .route(web::post().to(reposition_robot)),
+            )
+            .service(
+                web::resource("/reset_robot")
+                    .wrap(from_fn(idempotency))
+                    .route(web::post().to(reset_robot)),
+            )
             .route("/robot_position", web::get().to(robot_position))
+            .route("/jobs", web::post().to(create_job))
+            .route("/jobs/{id}", web::get().to(job_status))
+            .route("/jobs/{id}", web::delete().to(cancel_job))
+            .route("/limits", web::get().to(limit_usage))
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .route("/version", web::get().to(version))
+            .route("/events", web::get().to(list_events))
+            .route("/events/verify", web::get().to(verify_events))
+            .route("/robots/{id}/position", web::get().to(position_at))
+            .route("/robots/{id}/history", web::get().to(history))
+            .route("/robots/{id}/sensors", web::get().to(robot_sensors))
+            .route("/robots/{id}/script", web::post().to(run_script))
+            .route(
+                "/robots/{id}/run-script/{name}",
+                web::post().to(run_rhai_script),
+            )
+            .route("/simulation/clock", web::get().to(clock_status))
+            .route("/simulation/pause", web::post().to(pause_clock))
+            .route("/simulation/resume", web::post().to(resume_clock))
+            .route("/simulation/step", web::post().to(step_clock))
+            .route("/simulation/speed", web::put().to(set_speed))
+            .route("/render.txt", web::get().to(render_text))
+            .route("/render.svg", web::get().to(render_svg))
+            .route("/world", web::get().to(get_world))
+            .route("/world", web::put().to(put_world))
+            .route("/scripts", web::get().to(list_rhai_scripts))
+            .route("/scripts/{name}", web::put().to(save_rhai_script))
+            .route("/scripts/{name}", web::get().to(get_rhai_script))
+            .route("/scripts/{name}", web::delete().to(delete_rhai_script))
+            .route("/checkpoints", web::get().to(list_checkpoints))
+            .route("/checkpoints/{name}", web::put().to(save_checkpoint))
+            .route("/checkpoints/{name}", web::get().to(get_checkpoint))
+            .route("/checkpoints/{name}", web::delete().to(delete_checkpoint))
+            .route(
+                "/checkpoints/{name}/restore",
+                web::post().to(restore_checkpoint),
+            )
     })
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
     .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .run();
+
+    let stopper = rt::spawn(shutdown::stop_on_signal(
+        server.handle(),
+        draining_jobs,
+        shutdown_timeout,
+    ));
+    server.await?;
+    let cancelled_jobs = stopper.await.unwrap_or_default();
+    if let Some(simulation) = &final_simulation {
+        shutdown::settle_motion(
+            simulation,
+            &final_robots,
+            &final_log,
+            final_batteries
+                .as_ref()
+                .map(|batteries| batteries.get_ref()),
+            Some(&final_metrics),
+        );
+    }
+    Ok(shutdown::finish(&final_log, cancelled_jobs))
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{Direction, Robot};
+    use robot::solutions::{Direction, Robot};
 
     #[test]
     fn test_robot() {
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+
+use crate::controller::{RobotState, ENGINE};
+
+/// Upper bounds in seconds for request latency.
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Upper bounds in seconds for waiting on the robot lock.
+pub const LOCK_WAIT_BUCKETS: [f64; 6] = [0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0];
+
+/// A cumulative histogram with fixed buckets, in the Prometheus sense.
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let count = count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {count}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {count}"
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rejection {
+    /// An instruction other than `L`, `R` or `A`.
+    Invalid,
+    /// Refused by the per-request cap or the daily quota.
+    Limit,
+}
+
+impl Rejection {
+    fn label(self) -> &'static str {
+        match self {
+            Rejection::Invalid => "invalid",
+            Rejection::Limit => "limit",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    latency: Mutex<BTreeMap<(String, String), Histogram>>,
+    instructions_executed: AtomicU64,
+    instructions_rejected: Mutex<BTreeMap<Rejection, u64>>,
+}
+
+impl Metrics {
+    fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), route.to_owned(), status))
+            .or_default() += 1;
+        self.latency
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), route.to_owned()))
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(elapsed);
+    }
+
+    pub fn executed(&self, instructions: usize) {
+        self.instructions_executed
+            .fetch_add(instructions as u64, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, reason: Rejection, instructions: usize) {
+        *self
+            .instructions_rejected
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += instructions as u64;
+    }
+
+    pub fn render(&self, robots: &RobotState) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP robot_http_requests_total HTTP requests by route and status.\n");
+        out.push_str("# TYPE robot_http_requests_total counter\n");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "robot_http_requests_total{{method=\"{method}\",route=\"{route}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP robot_http_request_duration_seconds HTTP request latency by route.\n");
+        out.push_str("# TYPE robot_http_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in self.latency.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "robot_http_request_duration_seconds",
+                &format!("method=\"{method}\",route=\"{route}\""),
+            );
+        }
+
+        out.push_str("# HELP robot_instructions_executed_total Instructions applied to robots.\n");
+        out.push_str("# TYPE robot_instructions_executed_total counter\n");
+        let _ = writeln!(
+            out,
+            "robot_instructions_executed_total{{engine=\"{ENGINE}\"}} {}",
+            self.instructions_executed.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP robot_instructions_rejected_total Instructions refused before execution.\n",
+        );
+        out.push_str("# TYPE robot_instructions_rejected_total counter\n");
+        for (reason, count) in self.instructions_rejected.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "robot_instructions_rejected_total{{reason=\"{}\"}} {count}",
+                reason.label()
+            );
+        }
+
+        out.push_str(
+            "# HELP robot_state_lock_wait_seconds Time spent waiting for the robot state lock.\n",
+        );
+        out.push_str("# TYPE robot_state_lock_wait_seconds histogram\n");
+        robots
+            .lock_wait
+            .render(&mut out, "robot_state_lock_wait_seconds", "");
+
+        let robot_count = robots.lock().len();
+        out.push_str("# HELP robot_robots Robots currently placed.\n");
+        out.push_str("# TYPE robot_robots gauge\n");
+        let _ = writeln!(out, "robot_robots {robot_count}");
+
+        out
+    }
+}
+
+/// Count executed instructions when metrics are registered.
+pub fn record_executed(req: &HttpRequest, instructions: usize) {
+    if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+        metrics.executed(instructions);
+    }
+}
+
+/// Count rejected instructions when metrics are registered.
+pub fn record_rejected(req: &HttpRequest, reason: Rejection, instructions: usize) {
+    if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+        metrics.rejected(reason, instructions);
+    }
+}
+
+/// Record count and latency of every request, labelled by its route pattern.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(metrics) = req.app_data::<web::Data<Metrics>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+
+    let response = next.call(req).await?;
+    metrics.record_request(
+        &method,
+        &route,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    Ok(response.map_into_boxed_body())
+}
+
+/// Server metrics in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain")
+    )
+)]
+pub async fn metrics(metrics: web::Data<Metrics>, robots: web::Data<RobotState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&robots))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::{move_robot, RobotState};
+    use crate::metrics::{metrics, track_requests, Metrics};
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(Metrics::default()))
+                .wrap(from_fn(track_requests))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/metrics", web::get().to(metrics)),
+        )
+        .await;
+
+        for instructions in ["RAALAL", "RAX"] {
+            let req = TestRequest::post()
+                .uri("/move_robot")
+                .set_json(serde_json::json!({ "instructions": instructions }))
+                .to_request();
+            call_service(&app, req).await;
+        }
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            "robot_http_requests_total{method=\"POST\",route=\"/move_robot\",status=\"200\"} 1"
+        ));
+        assert!(body.contains(
+            "robot_http_requests_total{method=\"POST\",route=\"/move_robot\",status=\"400\"} 1"
+        ));
+        assert!(body.contains("robot_instructions_executed_total{engine=\"no_pattern\"} 6"));
+        assert!(body.contains("robot_instructions_rejected_total{reason=\"invalid\"} 1"));
+        assert!(body.contains("robot_robots 1"));
+        assert!(body.contains("robot_state_lock_wait_seconds_count"));
+    }
+}
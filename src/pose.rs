@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Heading {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Heading {
+    /// One cell forward as `(dx, dy)`, with north towards `+y`.
+    pub fn offset(self) -> (i32, i32) {
+        match self {
+            Heading::North => (0, 1),
+            Heading::East => (1, 0),
+            Heading::South => (0, -1),
+            Heading::West => (-1, 0),
+        }
+    }
+
+    pub fn left(self) -> Heading {
+        match self {
+            Heading::North => Heading::West,
+            Heading::East => Heading::North,
+            Heading::South => Heading::East,
+            Heading::West => Heading::South,
+        }
+    }
+
+    pub fn right(self) -> Heading {
+        match self {
+            Heading::North => Heading::East,
+            Heading::East => Heading::South,
+            Heading::South => Heading::West,
+            Heading::West => Heading::North,
+        }
+    }
+}
+
+/// A robot's pose independent of the engine compiled in, so that logs and
+/// snapshots written by one engine can be read by another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Pose {
+    pub x: i32,
+    pub y: i32,
+    pub facing: Heading,
+}
+
+impl Pose {
+    /// The pose after one `L`, `R` or `A`.
+    pub fn step(self, instruction: char) -> Pose {
+        match instruction {
+            'L' => Pose {
+                facing: self.facing.left(),
+                ..self
+            },
+            'R' => Pose {
+                facing: self.facing.right(),
+                ..self
+            },
+            _ => {
+                let (dx, dy) = self.facing.offset();
+                Pose {
+                    x: self.x.saturating_add(dx),
+                    y: self.y.saturating_add(dy),
+                    ..self
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "no_pattern")]
+mod engine {
+    use super::{Heading, Pose};
+    use crate::solutions::{Direction, Robot};
+
+    impl From<&Robot> for Pose {
+        fn from(robot: &Robot) -> Self {
+            let facing = match robot.facing {
+                Direction::North => Heading::North,
+                Direction::East => Heading::East,
+                Direction::South => Heading::South,
+                Direction::West => Heading::West,
+            };
+            Pose {
+                x: robot.x,
+                y: robot.y,
+                facing,
+            }
+        }
+    }
+
+    impl From<Pose> for Robot {
+        fn from(pose: Pose) -> Self {
+            let facing = match pose.facing {
+                Heading::North => Direction::North,
+                Heading::East => Direction::East,
+                Heading::South => Direction::South,
+                Heading::West => Direction::West,
+            };
+            Robot::new(pose.x, pose.y, facing)
+        }
+    }
+}
+
+#[cfg(feature = "type_state")]
+mod engine {
+    use super::{Heading, Pose};
+    use crate::solutions::{Robot, RobotWithFace};
+
+    impl From<&RobotWithFace> for Pose {
+        fn from(robot: &RobotWithFace) -> Self {
+            let (position, facing) = match robot {
+                RobotWithFace::North(robot) => (&robot.position, Heading::North),
+                RobotWithFace::East(robot) => (&robot.position, Heading::East),
+                RobotWithFace::South(robot) => (&robot.position, Heading::South),
+                RobotWithFace::West(robot) => (&robot.position, Heading::West),
+            };
+            Pose {
+                x: position.x,
+                y: position.y,
+                facing,
+            }
+        }
+    }
+
+    impl From<Pose> for RobotWithFace {
+        fn from(pose: Pose) -> Self {
+            match pose.facing {
+                Heading::North => RobotWithFace::North(Robot::new(pose.x, pose.y)),
+                Heading::East => RobotWithFace::East(Robot::new(pose.x, pose.y)),
+                Heading::South => RobotWithFace::South(Robot::new(pose.x, pose.y)),
+                Heading::West => RobotWithFace::West(Robot::new(pose.x, pose.y)),
+            }
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{robot_not_found, ErrorResponse, RobotId, RobotState, DEFAULT_ROBOT};
+use crate::events::{log_unreadable, EventLog};
+use crate::history::trail;
+use crate::pose::{Heading, Pose};
+use crate::world::{Cell, World, WorldMap};
+
+/// Size of a rendering that shows a single robot.
+const DEFAULT_WIDTH: u32 = 21;
+const DEFAULT_HEIGHT: u32 = 11;
+/// Cells left around the robots when the viewport is fitted to them.
+const MARGIN: u32 = 5;
+/// Largest viewport side, in cells.
+pub const MAX_VIEW: u32 = 200;
+/// Most trail cells drawn per robot.
+pub const MAX_TRAIL: usize = 1000;
+/// Pixels per cell in an SVG unless `scale` says otherwise.
+const DEFAULT_SCALE: u32 = 20;
+/// Largest pixels per cell in an SVG.
+pub const MAX_SCALE: u32 = 100;
+/// Trail cells an SVG draws per robot unless `trail` says otherwise.
+const DEFAULT_SVG_TRAIL: usize = 50;
+/// Colours robots and their trails cycle through, by robot id.
+const PALETTE: [&str; 8] = [
+    "#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+
+/// The rectangle of cells a rendering shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub center: Cell,
+    /// Columns, i.e. cells along `x`.
+    pub width: u32,
+    /// Rows, i.e. cells along `y`.
+    pub height: u32,
+}
+
+impl Viewport {
+    /// The lowest `x` shown.
+    pub fn min_x(&self) -> i32 {
+        self.center
+            .x
+            .saturating_sub((self.width.saturating_sub(1) / 2) as i32)
+    }
+
+    /// The highest `y` shown, drawn on the first row.
+    pub fn max_y(&self) -> i32 {
+        self.center.y.saturating_add((self.height / 2) as i32)
+    }
+
+    /// Cells row by row from the north-west corner, the way they are drawn.
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = Cell>> {
+        let (min_x, max_y, width) = (self.min_x(), self.max_y(), self.width as i32);
+        (0..self.height as i32).map(move |row| {
+            (0..width).map(move |column| Cell {
+                x: min_x.saturating_add(column),
+                y: max_y.saturating_sub(row),
+            })
+        })
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct RenderQuery {
+    /// Comma-separated ids of the robots to draw, e.g. `0,3`. All robots when absent.
+    pub robots: Option<String>,
+    /// Centre of the viewport. Defaults to the middle of the robots drawn.
+    pub x: Option<i32>,
+    pub y: Option<i32>,
+    /// Columns. Defaults to fitting the robots drawn, at least 21.
+    pub width: Option<u32>,
+    /// Rows. Defaults to fitting the robots drawn, at least 11.
+    pub height: Option<u32>,
+    /// Also mark the last `trail` cells each robot drove through, at most 1000. The SVG draws 50
+    /// by default.
+    pub trail: Option<usize>,
+    /// Pixels per cell in the SVG, 1 to 100 (default 20).
+    pub scale: Option<u32>,
+}
+
+impl RenderQuery {
+    /// Ids asked for, or `None` for all robots.
+    fn robot_ids(&self) -> Result<Option<Vec<RobotId>>, String> {
+        let Some(robots) = &self.robots else {
+            return Ok(None);
+        };
+        robots
+            .split(',')
+            .map(|id| {
+                id.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid robot id '{id}'"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// The viewport asked for, filling in what is missing to fit `robots`.
+    pub fn viewport(&self, robots: &[Pose]) -> Result<Viewport, String> {
+        // The middle of the values and how many cells they cover.
+        let span = |values: Vec<i32>| {
+            let (min, max) = (*values.iter().min()?, *values.iter().max()?);
+            let middle = (i64::from(min) + i64::from(max)) / 2;
+            Some((middle as i32, max.abs_diff(min).saturating_add(1)))
+        };
+        let xs = robots.iter().map(|pose| pose.x).collect();
+        let ys = robots.iter().map(|pose| pose.y).collect();
+        let (center_x, span_x) = span(xs).unwrap_or((0, 1));
+        let (center_y, span_y) = span(ys).unwrap_or((0, 1));
+        let fit = |span: u32, least| span.saturating_add(2 * MARGIN).clamp(least, MAX_VIEW);
+        let viewport = Viewport {
+            center: Cell {
+                x: self.x.unwrap_or(center_x),
+                y: self.y.unwrap_or(center_y),
+            },
+            width: self.width.unwrap_or_else(|| fit(span_x, DEFAULT_WIDTH)),
+            height: self.height.unwrap_or_else(|| fit(span_y, DEFAULT_HEIGHT)),
+        };
+        if [viewport.width, viewport.height]
+            .iter()
+            .any(|side| !(1..=MAX_VIEW).contains(side))
+        {
+            return Err(format!("width and height must be 1 to {MAX_VIEW}"));
+        }
+        Ok(viewport)
+    }
+}
+
+/// Why a query cannot be drawn.
+pub enum Unrenderable {
+    Invalid(String),
+    UnknownRobot(RobotId),
+}
+
+impl Unrenderable {
+    pub fn response(self) -> HttpResponse {
+        match self {
+            Unrenderable::Invalid(err) => HttpResponse::BadRequest().json(ErrorResponse::new(err)),
+            Unrenderable::UnknownRobot(id) => robot_not_found(id),
+        }
+    }
+}
+
+/// The robots a rendering draws, ordered by id, and the viewport around them.
+pub fn select(
+    robots: &RobotState,
+    query: &RenderQuery,
+) -> Result<(Vec<(RobotId, Pose)>, Viewport), Unrenderable> {
+    let ids = query.robot_ids().map_err(Unrenderable::Invalid)?;
+    if query.trail.is_some_and(|length| length > MAX_TRAIL) {
+        return Err(Unrenderable::Invalid(format!(
+            "trail must be at most {MAX_TRAIL}"
+        )));
+    }
+    let robots = robots.lock();
+    let mut drawn: Vec<(RobotId, Pose)> = match ids {
+        Some(ids) => ids
+            .into_iter()
+            .map(|id| match robots.get(&id) {
+                Some(entry) => Ok((id, Pose::from(&entry.robot))),
+                None => Err(Unrenderable::UnknownRobot(id)),
+            })
+            .collect::<Result<_, _>>()?,
+        None => robots
+            .iter()
+            .map(|(id, entry)| (*id, Pose::from(&entry.robot)))
+            .collect(),
+    };
+    drawn.sort_by_key(|(id, _)| *id);
+    let poses: Vec<Pose> = drawn.iter().map(|(_, pose)| *pose).collect();
+    let viewport = query.viewport(&poses).map_err(Unrenderable::Invalid)?;
+    Ok((drawn, viewport))
+}
+
+/// `^`, `>`, `v` or `<`.
+pub fn arrow(heading: Heading) -> char {
+    match heading {
+        Heading::North => '^',
+        Heading::East => '>',
+        Heading::South => 'v',
+        Heading::West => '<',
+    }
+}
+
+/// Draw the viewport as text, north up, one line per row.
+///
+/// Robots are arrows, `#` is an obstacle, `*` a cell on a trail, `+` a charging station, `.` a
+/// free cell and `~` lies outside the arena.
+pub fn ascii(
+    map: &WorldMap,
+    robots: &[Pose],
+    trail: &HashSet<Cell>,
+    viewport: &Viewport,
+) -> String {
+    let mut text = String::new();
+    for row in viewport.rows() {
+        for cell in row {
+            let robot = robots.iter().rev().find(|pose| Cell::from(**pose) == cell);
+            text.push(match robot {
+                Some(pose) => arrow(pose.facing),
+                None if map.bounds.is_some_and(|bounds| !bounds.contains(cell)) => '~',
+                None if map.obstacles.contains(&cell) => '#',
+                None if trail.contains(&cell) => '*',
+                None if map.charging_stations.contains(&cell) => '+',
+                None => '.',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// A plain-text map of the robots, the obstacles around them and optionally their trails.
+///
+/// North is up. Robots are drawn as `^ > v <`, obstacles as `#`, trail cells as `*`, charging
+/// stations as `+`, free cells as `.` and cells outside the arena as `~`. A line per robot with
+/// its pose follows the map.
+#[utoipa::path(
+    get,
+    path = "/render.txt",
+    params(RenderQuery),
+    responses(
+        (status = 200, description = "The map", body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid robot ids or viewport", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse)
+    )
+)]
+pub async fn render_text(
+    robots: web::Data<RobotState>,
+    world: web::Data<World>,
+    log: web::Data<EventLog>,
+    http: HttpRequest,
+    query: web::Query<RenderQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    let (drawn, viewport) = match select(&robots, &query) {
+        Ok(selected) => selected,
+        Err(err) => return err.response(),
+    };
+    let trails = drawn
+        .iter()
+        .filter_map(|(id, _)| query.trail.map(|length| trail(&log, *id, length)))
+        .collect::<io::Result<Vec<_>>>();
+    let trails: HashSet<Cell> = match trails {
+        Ok(trails) => trails.into_iter().flatten().flatten().collect(),
+        Err(err) => return log_unreadable(err),
+    };
+    let poses: Vec<Pose> = drawn.iter().map(|(_, pose)| *pose).collect();
+    let mut text = ascii(&world.map(), &poses, &trails, &viewport);
+    for (id, pose) in &drawn {
+        let _ = writeln!(
+            text,
+            "{} robot {id} at {},{}",
+            arrow(pose.facing),
+            pose.x,
+            pose.y
+        );
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(text)
+}
+
+/// A robot as an SVG draws it.
+pub struct DrawnRobot {
+    pub id: RobotId,
+    pub pose: Pose,
+    /// Recent paths, oldest first, as returned by [`trail`].
+    pub trail: Vec<Vec<Cell>>,
+}
+
+/// Draw the viewport as an SVG image, `scale` pixels per cell.
+///
+/// Shows the grid, the arena, charging stations, obstacles, each robot's trail as a polyline
+/// from a hollow start marker to a filled end marker, and each robot as a triangle pointing
+/// where it faces. Robots and their trails share a colour.
+pub fn svg(map: &WorldMap, robots: &[DrawnRobot], viewport: &Viewport, scale: u32) -> String {
+    let unit = f64::from(scale);
+    let (width, height) = (viewport.width * scale, viewport.height * scale);
+    let (min_x, max_y) = (i64::from(viewport.min_x()), i64::from(viewport.max_y()));
+    // Top-left corner of a cell in pixels.
+    let corner = |cell: Cell| {
+        (
+            (i64::from(cell.x) - min_x) as f64 * unit,
+            (max_y - i64::from(cell.y)) as f64 * unit,
+        )
+    };
+    let center = |cell: Cell| {
+        let (x, y) = corner(cell);
+        (x + unit / 2.0, y + unit / 2.0)
+    };
+    let visible: Vec<Cell> = viewport.rows().flatten().collect();
+    let mut image = String::new();
+    let _ = writeln!(
+        image,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+
+    let arena = match map.bounds {
+        Some(bounds) => {
+            let _ = writeln!(
+                image,
+                r##"<rect width="{width}" height="{height}" fill="#d9d9d9"/>"##
+            );
+            let (left, top) = corner(Cell {
+                x: bounds.min_x,
+                y: bounds.max_y,
+            });
+            let columns = f64::from(bounds.max_x) - f64::from(bounds.min_x) + 1.0;
+            let rows = f64::from(bounds.max_y) - f64::from(bounds.min_y) + 1.0;
+            (left, top, columns * unit, rows * unit)
+        }
+        None => (0.0, 0.0, f64::from(width), f64::from(height)),
+    };
+    let _ = writeln!(
+        image,
+        r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#ffffff"/>"##,
+        arena.0, arena.1, arena.2, arena.3
+    );
+
+    let mut grid = String::new();
+    for column in 0..=viewport.width {
+        let _ = write!(grid, "M{} 0V{height}", column * scale);
+    }
+    for row in 0..=viewport.height {
+        let _ = write!(grid, "M0 {}H{width}", row * scale);
+    }
+    let _ = writeln!(
+        image,
+        r##"<path d="{grid}" stroke="#ececec" stroke-width="1" fill="none"/>"##
+    );
+
+    for (cells, fill) in [
+        (&map.charging_stations, "#b7e4c7"),
+        (&map.obstacles, "#404040"),
+    ] {
+        for cell in visible.iter().filter(|cell| cells.contains(cell)) {
+            let (x, y) = corner(*cell);
+            let _ = writeln!(
+                image,
+                r#"<rect x="{x}" y="{y}" width="{scale}" height="{scale}" fill="{fill}"/>"#
+            );
+        }
+    }
+
+    for robot in robots {
+        let colour = PALETTE[robot.id as usize % PALETTE.len()];
+        for path in &robot.trail {
+            let (Some(first), Some(last)) = (path.first(), path.last()) else {
+                continue;
+            };
+            let points: Vec<String> = path
+                .iter()
+                .map(|cell| {
+                    let (x, y) = center(*cell);
+                    format!("{x},{y}")
+                })
+                .collect();
+            let _ = writeln!(
+                image,
+                r#"<polyline points="{}" stroke="{colour}" stroke-width="{}" stroke-linejoin="round" fill="none" opacity="0.7"/>"#,
+                points.join(" "),
+                unit / 5.0
+            );
+            let ((start_x, start_y), (end_x, end_y)) = (center(*first), center(*last));
+            let _ = writeln!(
+                image,
+                r##"<circle cx="{start_x}" cy="{start_y}" r="{}" stroke="{colour}" stroke-width="{}" fill="#ffffff"/>"##,
+                unit / 4.0,
+                unit / 10.0
+            );
+            let _ = writeln!(
+                image,
+                r#"<circle cx="{end_x}" cy="{end_y}" r="{}" fill="{colour}"/>"#,
+                unit / 4.0
+            );
+        }
+    }
+
+    for robot in robots {
+        let colour = PALETTE[robot.id as usize % PALETTE.len()];
+        let (x, y) = center(Cell::from(robot.pose));
+        // Forward and sideways in pixels; the y axis points south on screen.
+        let (dx, dy) = robot.pose.facing.offset();
+        let (forward_x, forward_y) = (f64::from(dx), -f64::from(dy));
+        let (side_x, side_y) = (-forward_y, forward_x);
+        let radius = unit * 0.4;
+        let points = [(1.0, 0.0), (-0.7, 0.7), (-0.7, -0.7)]
+            .iter()
+            .map(|(forward, side)| {
+                let point_x = x + (forward * forward_x + side * side_x) * radius;
+                let point_y = y + (forward * forward_y + side * side_y) * radius;
+                format!("{point_x},{point_y}")
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(
+            image,
+            r##"<polygon points="{points}" fill="{colour}" stroke="#000000" stroke-width="1"><title>robot {} at {},{} facing {:?}</title></polygon>"##,
+            robot.id, robot.pose.x, robot.pose.y, robot.pose.facing
+        );
+    }
+    image.push_str("</svg>\n");
+    image
+}
+
+/// An SVG image of the robots, their recent trails and the map around them.
+///
+/// Takes the same parameters as `/render.txt`, plus `scale`. Trails of the last 50 cells are
+/// drawn unless `trail` says otherwise; `trail=0` leaves them out.
+#[utoipa::path(
+    get,
+    path = "/render.svg",
+    params(RenderQuery),
+    responses(
+        (status = 200, description = "The image", body = String, content_type = "image/svg+xml"),
+        (status = 400, description = "Invalid robot ids, viewport or scale", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse)
+    )
+)]
+pub async fn render_svg(
+    robots: web::Data<RobotState>,
+    world: web::Data<World>,
+    log: web::Data<EventLog>,
+    http: HttpRequest,
+    query: web::Query<RenderQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    let scale = query.scale.unwrap_or(DEFAULT_SCALE);
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "scale must be 1 to {MAX_SCALE}"
+        )));
+    }
+    let (drawn, viewport) = match select(&robots, &query) {
+        Ok(selected) => selected,
+        Err(err) => return err.response(),
+    };
+    let length = query.trail.unwrap_or(DEFAULT_SVG_TRAIL);
+    let drawn = drawn
+        .into_iter()
+        .map(|(id, pose)| {
+            Ok(DrawnRobot {
+                id,
+                pose,
+                trail: trail(&log, id, length)?,
+            })
+        })
+        .collect::<io::Result<Vec<DrawnRobot>>>();
+    let drawn = match drawn {
+        Ok(drawn) => drawn,
+        Err(err) => return log_unreadable(err),
+    };
+    HttpResponse::Ok().content_type("image/svg+xml").body(svg(
+        &world.map(),
+        &drawn,
+        &viewport,
+        scale,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::{move_robot, reposition_robot, RobotState};
+    use crate::events::EventLog;
+    use crate::pose::{Heading, Pose};
+    use crate::render::{ascii, render_svg, render_text, svg, DrawnRobot, Viewport};
+    use crate::storage::TempStorage;
+    use crate::world::{Bounds, Cell, World, WorldMap, WORLD_FILE};
+
+    #[test]
+    fn test_ascii() {
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: 0,
+                min_y: 0,
+                max_x: 9,
+                max_y: 9,
+            }),
+            obstacles: [Cell { x: 1, y: 1 }].into(),
+            charging_stations: [Cell { x: 0, y: 2 }].into(),
+        };
+        let robot = Pose {
+            x: 0,
+            y: 0,
+            facing: Heading::East,
+        };
+        let viewport = Viewport {
+            center: Cell { x: 0, y: 1 },
+            width: 4,
+            height: 4,
+        };
+        assert_eq!(
+            ascii(&map, &[robot], &HashSet::new(), &viewport),
+            "~...\n~+..\n~.#.\n~>..\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_render_text() {
+        let storage = TempStorage::new("render");
+        let map = WorldMap {
+            obstacles: [Cell { x: 2, y: 1 }].into(),
+            ..WorldMap::default()
+        };
+        storage.write_json(WORLD_FILE, &map).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(World::load(storage.clone()).unwrap()))
+                .app_data(web::Data::new(EventLog::open(&storage).unwrap()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot))
+                .route("/render.txt", web::get().to(render_text))
+                .route("/render.svg", web::get().to(render_svg)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "ARA" }))
+            .to_request();
+        call_service(&app, req).await;
+        let req = TestRequest::post()
+            .uri("/reposition_robot?robot_id=1")
+            .set_json(serde_json::json!({ "x": 3, "y": 0, "facing": "West" }))
+            .to_request();
+        call_service(&app, req).await;
+        let render = |query: &str| {
+            TestRequest::get()
+                .uri(&format!("/render.txt?{query}"))
+                .to_request()
+        };
+
+        let text = call_and_read_body(&app, render("x=1&y=1&width=5&height=3&trail=10")).await;
+        assert_eq!(
+            String::from_utf8(text.to_vec()).unwrap(),
+            ".....\n.*>#.\n.*..<\n> robot 0 at 1,1\n< robot 1 at 3,0\n"
+        );
+        let text = call_and_read_body(&app, render("x=1&y=1&width=5&height=3&trail=2")).await;
+        assert!(text.starts_with(b".....\n.*>#.\n....<\n"));
+        let resp = call_service(&app, render("trail=100000")).await;
+        assert_eq!(resp.status(), 400);
+        let text = call_and_read_body(&app, render("robots=1&width=3&height=1")).await;
+        assert_eq!(text, ".<.\n< robot 1 at 3,0\n".as_bytes());
+        let text = call_and_read_body(&app, render("robots=0,1")).await;
+        assert_eq!(text.iter().filter(|byte| **byte == b'\n').count(), 14);
+
+        let req = TestRequest::get()
+            .uri("/render.svg?robots=0&scale=10")
+            .to_request();
+        let image = String::from_utf8(call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(
+            image.contains(r#"<polyline points="95,65 95,55 105,55""#),
+            "{image}"
+        );
+        let req = TestRequest::get().uri("/render.svg?scale=0").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        assert_eq!(call_service(&app, render("robots=7")).await.status(), 404);
+        assert_eq!(call_service(&app, render("robots=x")).await.status(), 400);
+        assert_eq!(call_service(&app, render("width=0")).await.status(), 400);
+    }
+
+    #[test]
+    fn test_svg() {
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: 0,
+                min_y: 0,
+                max_x: 1,
+                max_y: 1,
+            }),
+            obstacles: [Cell { x: 1, y: 1 }, Cell { x: 5, y: 5 }].into(),
+            ..WorldMap::default()
+        };
+        let robot = DrawnRobot {
+            id: 1,
+            pose: Pose {
+                x: 1,
+                y: 0,
+                facing: Heading::East,
+            },
+            trail: vec![vec![
+                Cell { x: 0, y: 1 },
+                Cell { x: 0, y: 0 },
+                Cell { x: 1, y: 0 },
+            ]],
+        };
+        let viewport = Viewport {
+            center: Cell { x: 1, y: 1 },
+            width: 3,
+            height: 3,
+        };
+        let image = svg(&map, &[robot], &viewport, 10);
+        assert!(
+            image.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="30" height="30""#)
+        );
+        assert!(image.ends_with("</svg>\n"));
+        // The arena leaves out the top row (y = 2) and the right column (x = 2) of the viewport.
+        assert!(image.contains(r##"<rect x="0" y="10" width="20" height="20" fill="#ffffff"/>"##));
+        // Only the obstacle inside the viewport is drawn.
+        assert_eq!(image.matches(r##"fill="#404040""##).count(), 1);
+        assert!(image.contains(r##"<polyline points="5,15 5,25 15,25" stroke="#d62728""##));
+        assert!(image.contains(r##"<polygon points="19,25 12.2,27.8 12.2,22.2" fill="#d62728""##));
+    }
+}
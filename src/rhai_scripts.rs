@@ -0,0 +1,488 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{ErrorResponse, RobotId, RobotModel, RobotState, DEFAULT_ROBOT};
+use crate::metrics::{record_rejected, Rejection};
+use crate::pose::{Heading, Pose};
+use crate::script::{run_on_robot, Run, ScriptContext, ScriptResult, StopReason};
+use crate::storage::Storage;
+use crate::world::{Cell, Reading, World, WorldMap};
+
+/// File in the data directory holding the stored Rhai scripts.
+pub const RHAI_SCRIPTS_FILE: &str = "rhai_scripts.json";
+
+/// Rhai operations one run may take before it is stopped.
+pub const MAX_OPERATIONS: u64 = 100_000;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct RhaiScript {
+    pub name: String,
+    pub source: String,
+    /// Milliseconds since the Unix epoch.
+    pub updated_at_ms: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RhaiScriptUpload {
+    /// e.g. `while robot.front_clear() { robot.advance(); }`.
+    pub source: String,
+}
+
+/// Stored Rhai scripts, written through to the data directory on every change.
+pub struct RhaiScripts {
+    storage: Storage,
+    scripts: Mutex<BTreeMap<String, RhaiScript>>,
+}
+
+impl RhaiScripts {
+    pub fn load(storage: Storage) -> io::Result<Self> {
+        let scripts = storage.read_json(RHAI_SCRIPTS_FILE)?.unwrap_or_default();
+        Ok(RhaiScripts {
+            storage,
+            scripts: Mutex::new(scripts),
+        })
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut BTreeMap<String, RhaiScript>) -> T) -> io::Result<T> {
+        let mut scripts = self.scripts.lock().unwrap();
+        let mut updated = scripts.clone();
+        let result = f(&mut updated);
+        self.storage.write_json(RHAI_SCRIPTS_FILE, &updated)?;
+        *scripts = updated;
+        Ok(result)
+    }
+
+    fn get(&self, name: &str) -> Option<RhaiScript> {
+        self.scripts.lock().unwrap().get(name).cloned()
+    }
+}
+
+struct HandleState {
+    robot: RobotModel,
+    instructions: Vec<char>,
+    /// Energy left, when batteries are enabled.
+    energy: Option<f64>,
+    depleted: bool,
+    /// The last move was refused because it would have run into the world map.
+    collided: bool,
+}
+
+/// The `robot` a script drives. Moves are recorded and applied once the script ends.
+#[derive(Clone)]
+struct RobotHandle {
+    state: Rc<RefCell<HandleState>>,
+    map: Rc<WorldMap>,
+    others: Rc<HashSet<Cell>>,
+    /// Energy an `A` and a turn use, when batteries are enabled.
+    costs: Option<(f64, f64)>,
+}
+
+impl RobotHandle {
+    fn pose(&self) -> Pose {
+        Pose::from(&self.state.borrow().robot)
+    }
+
+    fn step(&mut self, instruction: char) -> Result<(), Box<EvalAltResult>> {
+        let mut state = self.state.borrow_mut();
+        if let Some(blocked) = self.map.collision(Pose::from(&state.robot), &[instruction]) {
+            state.collided = true;
+            return Err(blocked.to_string().into());
+        }
+        if let (Some(energy), Some((advance, turn))) = (state.energy, self.costs) {
+            let cost = if instruction == 'A' { advance } else { turn };
+            if cost > energy {
+                state.depleted = true;
+                return Err("battery ran out".into());
+            }
+            state.energy = Some(energy - cost);
+        }
+        state.robot.execute(instruction);
+        state.instructions.push(instruction);
+        Ok(())
+    }
+
+    fn look(&mut self, turn: fn(Heading) -> Heading) -> Reading {
+        let pose = self.pose();
+        let cell = Cell::from(pose).towards(turn(pose.facing), 1);
+        self.map.probe(cell, &self.others)
+    }
+}
+
+/// A Rhai engine without file access, imports or `eval`, and with bounded resources.
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(10_000)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .on_print(|text| tracing::info!(text, "script print"))
+        .on_debug(|text, _, position| tracing::debug!(text, %position, "script debug"));
+    engine
+        .register_type_with_name::<RobotHandle>("Robot")
+        .register_fn("turn_left", |robot: &mut RobotHandle| robot.step('L'))
+        .register_fn("turn_right", |robot: &mut RobotHandle| robot.step('R'))
+        .register_fn("advance", |robot: &mut RobotHandle| robot.step('A'))
+        .register_fn("front", |robot: &mut RobotHandle| {
+            robot.look(|heading| heading).name()
+        })
+        .register_fn("left", |robot: &mut RobotHandle| {
+            robot.look(Heading::left).name()
+        })
+        .register_fn("right", |robot: &mut RobotHandle| {
+            robot.look(Heading::right).name()
+        })
+        .register_fn("front_clear", |robot: &mut RobotHandle| {
+            robot.look(|heading| heading) == Reading::Free
+        })
+        .register_fn("facing_north", |robot: &mut RobotHandle| {
+            robot.pose().facing == Heading::North
+        })
+        .register_fn("at", |robot: &mut RobotHandle, x: INT, y: INT| {
+            let pose = robot.pose();
+            INT::from(pose.x) == x && INT::from(pose.y) == y
+        })
+        .register_fn("obstacle_distance", |robot: &mut RobotHandle| {
+            let pose = robot.pose();
+            robot
+                .map
+                .distance_ahead(Cell::from(pose), pose.facing, &robot.others)
+                .map_or(Dynamic::UNIT, |distance| Dynamic::from(INT::from(distance)))
+        })
+        .register_get("x", |robot: &mut RobotHandle| INT::from(robot.pose().x))
+        .register_get("y", |robot: &mut RobotHandle| INT::from(robot.pose().y))
+        .register_get("facing", |robot: &mut RobotHandle| {
+            format!("{:?}", robot.pose().facing)
+        });
+    engine
+}
+
+/// Run a compiled script against the robot described by `context`.
+fn run_rhai(engine: &mut Engine, ast: &AST, context: &ScriptContext) -> Run {
+    let handle = RobotHandle {
+        state: Rc::new(RefCell::new(HandleState {
+            robot: context.start.clone(),
+            instructions: Vec::new(),
+            energy: context.batteries.map(|batteries| {
+                batteries.energy(context.robot_id, context.settled, &context.queued)
+            }),
+            depleted: false,
+            collided: false,
+        })),
+        map: Rc::new(context.map.clone()),
+        others: Rc::new(context.others.clone()),
+        costs: context
+            .batteries
+            .map(|batteries| (batteries.cost('A'), batteries.cost('L'))),
+    };
+    let operations = Rc::new(RefCell::new(0));
+    let counter = operations.clone();
+    engine.on_progress(move |done| {
+        *counter.borrow_mut() = done;
+        None
+    });
+    let mut scope = Scope::new();
+    scope.push("robot", handle.clone());
+    let result = engine.run_ast_with_scope(&mut scope, ast);
+
+    let state = handle.state.borrow();
+    let (stop, error) = match result {
+        Ok(()) => (StopReason::Completed, None),
+        Err(err) => match *err {
+            EvalAltResult::ErrorTooManyOperations(_) => (StopReason::StepBudget, None),
+            _ if state.depleted => (StopReason::BatteryDepleted, None),
+            _ if state.collided => (StopReason::Collision, None),
+            err => (StopReason::Error, Some(err.to_string())),
+        },
+    };
+    let steps = *operations.borrow();
+    Run {
+        robot: state.robot.clone(),
+        instructions: state.instructions.clone(),
+        steps,
+        stop,
+        error,
+    }
+}
+
+fn invalid_name(name: &str) -> Option<HttpResponse> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (!valid).then(|| {
+        HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "script names are 1 to {MAX_NAME_LEN} letters, digits, '-' or '_'"
+        )))
+    })
+}
+
+fn script_not_found(name: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(format!("script {name} not found")))
+}
+
+fn storage_failed(err: io::Error) -> HttpResponse {
+    tracing::error!(%err, "script write failed");
+    HttpResponse::InternalServerError()
+        .json(ErrorResponse::new(format!("could not save scripts: {err}")))
+}
+
+/// Store a Rhai script under a name, replacing any script with the same name.
+///
+/// Scripts drive `robot` with `turn_left()`, `turn_right()` and `advance()`, and sense with
+/// `front()`, `left()`, `right()`, `front_clear()`, `facing_north()`, `at(x, y)` and
+/// `obstacle_distance()`. `robot.x`, `robot.y` and `robot.facing` give the pose.
+#[utoipa::path(
+    put,
+    path = "/scripts/{name}",
+    params(("name" = String, Path, description = "Script name")),
+    request_body = RhaiScriptUpload,
+    responses(
+        (status = 200, description = "Script stored", body = RhaiScript),
+        (status = 400, description = "Invalid name or script does not compile", body = ErrorResponse)
+    )
+)]
+pub async fn save_rhai_script(
+    scripts: web::Data<RhaiScripts>,
+    http: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<RhaiScriptUpload>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if let Some(response) = authorize(&http, Permission::EditScripts, DEFAULT_ROBOT) {
+        return response;
+    }
+    if let Some(response) = invalid_name(&name) {
+        return response;
+    }
+    if let Err(err) = sandboxed_engine().compile(&req.source) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+    }
+    let script = RhaiScript {
+        name: name.clone(),
+        source: req.into_inner().source,
+        updated_at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    };
+    match scripts.update(|scripts| scripts.insert(name, script.clone())) {
+        Ok(_) => {
+            tracing::info!(script = %script.name, "script stored");
+            HttpResponse::Ok().json(script)
+        }
+        Err(err) => storage_failed(err),
+    }
+}
+
+/// All stored scripts, ordered by name.
+#[utoipa::path(
+    get,
+    path = "/scripts",
+    responses(
+        (status = 200, description = "Stored scripts", body = Vec<RhaiScript>)
+    )
+)]
+pub async fn list_rhai_scripts(
+    scripts: web::Data<RhaiScripts>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    let scripts: Vec<RhaiScript> = scripts.scripts.lock().unwrap().values().cloned().collect();
+    HttpResponse::Ok().json(scripts)
+}
+
+/// Get one stored script.
+#[utoipa::path(
+    get,
+    path = "/scripts/{name}",
+    params(("name" = String, Path, description = "Script name")),
+    responses(
+        (status = 200, description = "The script", body = RhaiScript),
+        (status = 404, description = "Unknown script", body = ErrorResponse)
+    )
+)]
+pub async fn get_rhai_script(
+    scripts: web::Data<RhaiScripts>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    match scripts.get(&path) {
+        Some(script) => HttpResponse::Ok().json(script),
+        None => script_not_found(&path),
+    }
+}
+
+/// Delete a stored script.
+#[utoipa::path(
+    delete,
+    path = "/scripts/{name}",
+    params(("name" = String, Path, description = "Script name")),
+    responses(
+        (status = 204, description = "Script deleted"),
+        (status = 404, description = "Unknown script", body = ErrorResponse)
+    )
+)]
+pub async fn delete_rhai_script(
+    scripts: web::Data<RhaiScripts>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::EditScripts, DEFAULT_ROBOT) {
+        return response;
+    }
+    match scripts.update(|scripts| scripts.remove(path.as_str())) {
+        Ok(Some(_)) => HttpResponse::NoContent().finish(),
+        Ok(None) => script_not_found(&path),
+        Err(err) => storage_failed(err),
+    }
+}
+
+/// Run a stored Rhai script on the robot.
+///
+/// Runs are sandboxed: no file access or imports, and at most 100000 operations, after
+/// which the script stops with `step_budget`. `steps` reports the operations used.
+#[utoipa::path(
+    post,
+    path = "/robots/{id}/run-script/{name}",
+    params(
+        ("id" = u32, Path, description = "Robot id"),
+        ("name" = String, Path, description = "Script name")
+    ),
+    responses(
+        (status = 200, description = "Script ran, see stop for why it ended", body = ScriptResult),
+        (status = 400, description = "Script does not compile", body = ErrorResponse),
+        (status = 404, description = "Unknown robot or script", body = ErrorResponse),
+        (status = 409, description = "Robots changed while the script ran, nothing was applied", body = ErrorResponse),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 413, description = "Too many instructions for one request", body = ErrorResponse),
+        (status = 429, description = "Daily instruction quota exceeded", body = ErrorResponse)
+    )
+)]
+#[tracing::instrument(skip_all, fields(robot_id = path.0, script = %path.1))]
+pub async fn run_rhai_script(
+    scripts: web::Data<RhaiScripts>,
+    robots: web::Data<RobotState>,
+    world: web::Data<World>,
+    http: HttpRequest,
+    path: web::Path<(RobotId, String)>,
+) -> impl Responder {
+    let (robot_id, name) = path.into_inner();
+    if let Some(response) = authorize(&http, Permission::Move, robot_id) {
+        return response;
+    }
+    let Some(script) = scripts.get(&name) else {
+        return script_not_found(&name);
+    };
+    let mut engine = sandboxed_engine();
+    let ast = match engine.compile(&script.source) {
+        Ok(ast) => ast,
+        Err(err) => {
+            record_rejected(&http, Rejection::Invalid, 1);
+            return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+        }
+    };
+    run_on_robot(&robots, &world, &http, robot_id, |context| {
+        let run = run_rhai(&mut engine, &ast, context);
+        let plan = context.plan(&run.instructions);
+        (run, plan)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::RobotState;
+    use crate::rhai_scripts::{run_rhai_script, save_rhai_script, RhaiScripts};
+    use crate::storage::TempStorage;
+    use crate::world::{Bounds, World, WorldMap, WORLD_FILE};
+
+    #[actix_web::test]
+    async fn test_stored_script_drives_the_robot() {
+        let storage = TempStorage::new("rhai");
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: 0,
+                min_y: 0,
+                max_x: 3,
+                max_y: 3,
+            }),
+            ..WorldMap::default()
+        };
+        storage.write_json(WORLD_FILE, &map).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(World::load(storage.clone()).unwrap()))
+                .app_data(web::Data::new(RhaiScripts::load(storage.clone()).unwrap()))
+                .route("/scripts/{name}", web::put().to(save_rhai_script))
+                .route(
+                    "/robots/{id}/run-script/{name}",
+                    web::post().to(run_rhai_script),
+                ),
+        )
+        .await;
+        let save = |name: &str, source: &str| {
+            TestRequest::put()
+                .uri(&format!("/scripts/{name}"))
+                .set_json(serde_json::json!({ "source": source }))
+                .to_request()
+        };
+        let run = |name: &str| {
+            TestRequest::post()
+                .uri(&format!("/robots/0/run-script/{name}"))
+                .to_request()
+        };
+
+        let source = r#"
+            while robot.front_clear() { robot.advance(); }
+            if robot.left() == "edge" { robot.turn_right(); }
+            robot.advance();
+        "#;
+        call_service(&app, save("corner", source)).await;
+        let result: serde_json::Value = call_and_read_body_json(&app, run("corner")).await;
+        assert_eq!(
+            result["pose"],
+            serde_json::json!({ "x": 1, "y": 3, "facing": "East" })
+        );
+        assert_eq!(result["instructions"], "AAARA");
+        assert_eq!(result["stop"], "completed");
+
+        call_service(&app, save("spin", "loop { robot.turn_left(); }")).await;
+        let result: serde_json::Value = call_and_read_body_json(&app, run("spin")).await;
+        assert_eq!(result["stop"], "step_budget");
+
+        call_service(&app, save("escape", r#"import "secrets" as s;"#)).await;
+        let result: serde_json::Value = call_and_read_body_json(&app, run("escape")).await;
+        assert_eq!(result["stop"], "error");
+
+        let resp = call_service(&app, save("broken", "robot.advance(")).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(call_service(&app, run("missing")).await.status(), 404);
+    }
+}
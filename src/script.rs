@@ -0,0 +1,729 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{authorize, Permission};
+use crate::battery::{Batteries, Plan};
+use crate::controller::{
+    precondition_failed, robot_not_found, ErrorResponse, RobotId, RobotModel, RobotState,
+};
+use crate::events::{record_event, Change};
+use crate::limits::charge_instructions;
+use crate::metrics::{record_executed, record_rejected, Rejection};
+use crate::pose::{Heading, Pose};
+use crate::sensors::other_robots;
+use crate::simulation::Simulation;
+use crate::world::{Cell, Reading, World, WorldMap};
+
+/// Steps a script may take when the request sets no budget, and the most it may ask for.
+pub const MAX_STEP_BUDGET: u64 = 10_000;
+/// Blocks, `else if`s and `!`s a script may nest, so parsing and running it stay off the
+/// bottom of the stack.
+pub const MAX_NESTING: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    FrontClear,
+    FacingNorth,
+    At(i32, i32),
+    Not(Box<Condition>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Move(char),
+    While(Condition, Vec<Statement>),
+    If(Condition, Vec<Statement>, Vec<Statement>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScriptError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i32),
+    Symbol(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            // Comments run to the end of the line.
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+            {
+                word.push(c);
+            }
+            tokens.push((position, Token::Word(word)));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::from(c);
+            chars.next();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                number.push(c);
+            }
+            let number = number.parse().map_err(|_| ScriptError {
+                position,
+                message: format!("invalid number '{number}'"),
+            })?;
+            tokens.push((position, Token::Number(number)));
+        } else if "{}(),!".contains(c) {
+            chars.next();
+            tokens.push((position, Token::Symbol(c)));
+        } else {
+            return Err(ScriptError {
+                position,
+                message: format!("unexpected '{c}'"),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ScriptError> {
+        Err(ScriptError {
+            position: self.position(),
+            message: message.into(),
+        })
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ScriptError> {
+        if self.peek() != Some(&Token::Symbol(symbol)) {
+            return self.error(format!("expected '{symbol}'"));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Parse something nested one level deeper than where the parser is.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ScriptError>,
+    ) -> Result<T, ScriptError> {
+        if self.depth == MAX_NESTING {
+            return self.error(format!("nested deeper than {MAX_NESTING} levels"));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn number(&mut self) -> Result<i32, ScriptError> {
+        match self.peek() {
+            Some(&Token::Number(number)) => {
+                self.next += 1;
+                Ok(number)
+            }
+            _ => self.error("expected a number"),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, ScriptError> {
+        let word = match self.peek() {
+            Some(Token::Symbol('!')) => {
+                self.next += 1;
+                let condition = self.nested(Self::condition)?;
+                return Ok(Condition::Not(Box::new(condition)));
+            }
+            Some(Token::Word(word)) => word.clone(),
+            _ => return self.error("expected a condition"),
+        };
+        let condition = match word.as_str() {
+            "front_clear" => Condition::FrontClear,
+            "facing_north" => Condition::FacingNorth,
+            "at" => {
+                self.next += 1;
+                self.expect('(')?;
+                let x = self.number()?;
+                self.expect(',')?;
+                let y = self.number()?;
+                self.expect(')')?;
+                return Ok(Condition::At(x, y));
+            }
+            _ => return self.error(format!("unknown condition '{word}'")),
+        };
+        self.next += 1;
+        Ok(condition)
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, ScriptError> {
+        self.expect('{')?;
+        let body = self.nested(Self::statements)?;
+        self.expect('}')?;
+        Ok(body)
+    }
+
+    /// Statements up to the end of the source or a closing brace.
+    fn statements(&mut self) -> Result<Vec<Statement>, ScriptError> {
+        let mut statements = Vec::new();
+        while let Some(token) = self.peek() {
+            let Token::Word(word) = token else {
+                if token == &Token::Symbol('}') {
+                    break;
+                }
+                return self.error("expected an instruction, while or if");
+            };
+            match word.as_str() {
+                "while" => {
+                    self.next += 1;
+                    let condition = self.condition()?;
+                    statements.push(Statement::While(condition, self.block()?));
+                }
+                "if" => statements.push(self.if_statement()?),
+                word if word.chars().all(|c| matches!(c, 'L' | 'R' | 'A')) => {
+                    statements.extend(word.chars().map(Statement::Move));
+                    self.next += 1;
+                }
+                _ => return self.error(format!("unknown instruction '{word}'")),
+            }
+        }
+        Ok(statements)
+    }
+
+    fn if_statement(&mut self) -> Result<Statement, ScriptError> {
+        self.next += 1;
+        let condition = self.condition()?;
+        let then = self.block()?;
+        let otherwise = match self.peek() {
+            Some(Token::Word(word)) if word == "else" => {
+                self.next += 1;
+                match self.peek() {
+                    Some(Token::Word(word)) if word == "if" => {
+                        vec![self.nested(Self::if_statement)?]
+                    }
+                    _ => self.block()?,
+                }
+            }
+            _ => Vec::new(),
+        };
+        Ok(Statement::If(condition, then, otherwise))
+    }
+}
+
+/// Parse a script such as `while front_clear { A } if at(3, 4) { L } else { R }`.
+pub fn parse(source: &str) -> Result<Vec<Statement>, ScriptError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        next: 0,
+        end: source.len(),
+        depth: 0,
+    };
+    let statements = parser.statements()?;
+    if parser.peek().is_some() {
+        return parser.error("unmatched '}'");
+    }
+    Ok(statements)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    Completed,
+    /// The step budget ran out, e.g. in an endless loop.
+    StepBudget,
+    BatteryDepleted,
+    /// The next `A` would have driven into an obstacle or off the arena.
+    Collision,
+    /// The script failed, see the error.
+    Error,
+}
+
+pub struct Limits {
+    /// Conditions checked plus instructions carried out.
+    pub steps: u64,
+    /// Instructions the battery powers, unlimited when absent.
+    pub instructions: Option<usize>,
+}
+
+pub struct Run {
+    pub robot: RobotModel,
+    pub instructions: Vec<char>,
+    pub steps: u64,
+    pub stop: StopReason,
+    pub error: Option<String>,
+}
+
+struct Interpreter<'a> {
+    map: &'a WorldMap,
+    others: &'a HashSet<Cell>,
+    robot: RobotModel,
+    limits: Limits,
+    steps: u64,
+    instructions: Vec<char>,
+}
+
+impl Interpreter<'_> {
+    fn spend(&mut self) -> Result<(), StopReason> {
+        if self.steps >= self.limits.steps {
+            return Err(StopReason::StepBudget);
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    fn check(&self, condition: &Condition) -> bool {
+        let pose = Pose::from(&self.robot);
+        match condition {
+            Condition::FrontClear => {
+                let ahead = Cell::from(pose).towards(pose.facing, 1);
+                self.map.probe(ahead, self.others) == Reading::Free
+            }
+            Condition::FacingNorth => pose.facing == Heading::North,
+            Condition::At(x, y) => pose.x == *x && pose.y == *y,
+            Condition::Not(condition) => !self.check(condition),
+        }
+    }
+
+    fn holds(&mut self, condition: &Condition) -> Result<bool, StopReason> {
+        self.spend()?;
+        Ok(self.check(condition))
+    }
+
+    fn run(&mut self, statements: &[Statement]) -> Result<(), StopReason> {
+        for statement in statements {
+            match statement {
+                Statement::Move(instruction) => {
+                    let pose = Pose::from(&self.robot);
+                    if self.map.collision(pose, &[*instruction]).is_some() {
+                        return Err(StopReason::Collision);
+                    }
+                    if self.limits.instructions == Some(self.instructions.len()) {
+                        return Err(StopReason::BatteryDepleted);
+                    }
+                    self.spend()?;
+                    self.robot.execute(*instruction);
+                    self.instructions.push(*instruction);
+                }
+                Statement::While(condition, body) => {
+                    while self.holds(condition)? {
+                        self.run(body)?;
+                    }
+                }
+                Statement::If(condition, then, otherwise) => {
+                    if self.holds(condition)? {
+                        self.run(then)?;
+                    } else {
+                        self.run(otherwise)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a script from `start`, sensing the world map and the cells other robots stand on.
+pub fn execute(
+    statements: &[Statement],
+    map: &WorldMap,
+    others: &HashSet<Cell>,
+    start: RobotModel,
+    limits: Limits,
+) -> Run {
+    let mut interpreter = Interpreter {
+        map,
+        others,
+        robot: start,
+        limits,
+        steps: 0,
+        instructions: Vec::new(),
+    };
+    let stop = match interpreter.run(statements) {
+        Ok(()) => StopReason::Completed,
+        Err(reason) => reason,
+    };
+    Run {
+        robot: interpreter.robot,
+        instructions: interpreter.instructions,
+        steps: interpreter.steps,
+        stop,
+        error: None,
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ScriptRequest {
+    /// e.g. `while front_clear { A } if at(3, 4) { L } else { R }`.
+    pub source: String,
+    /// Conditions checked plus instructions carried out before the script is stopped,
+    /// at most and by default 10000.
+    pub step_budget: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ScriptResult {
+    pub pose: RobotModel,
+    /// Instructions carried out, in order.
+    pub instructions: String,
+    pub steps: u64,
+    pub stop: StopReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<f64>,
+}
+
+/// Run a script with loops and conditions on the robot's sensors.
+///
+/// The instructions it carries out are applied, or queued in simulation mode, as one move.
+#[utoipa::path(
+    post,
+    path = "/robots/{id}/script",
+    params(("id" = u32, Path, description = "Robot id")),
+    request_body = ScriptRequest,
+    responses(
+        (status = 200, description = "Script ran, see stop for why it ended", body = ScriptResult),
+        (status = 400, description = "Invalid script or step budget", body = ErrorResponse),
+        (status = 404, description = "Unknown robot", body = ErrorResponse),
+        (status = 409, description = "Robots changed while the script ran, nothing was applied", body = ErrorResponse),
+        (status = 412, description = "If-Match does not match the robot version", body = ErrorResponse),
+        (status = 413, description = "Too many instructions for one request", body = ErrorResponse),
+        (status = 429, description = "Daily instruction quota exceeded", body = ErrorResponse)
+    )
+)]
+#[tracing::instrument(skip_all, fields(robot_id = *path))]
+pub async fn run_script(
+    robots: web::Data<RobotState>,
+    world: web::Data<World>,
+    http: HttpRequest,
+    path: web::Path<RobotId>,
+    req: web::Json<ScriptRequest>,
+) -> impl Responder {
+    let robot_id = *path;
+    if let Some(response) = authorize(&http, Permission::Move, robot_id) {
+        return response;
+    }
+    let statements = match parse(&req.source) {
+        Ok(statements) => statements,
+        Err(err) => {
+            record_rejected(&http, Rejection::Invalid, 1);
+            tracing::debug!(%err, "rejected script");
+            return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+        }
+    };
+    let budget = req.step_budget.unwrap_or(MAX_STEP_BUDGET);
+    if !(1..=MAX_STEP_BUDGET).contains(&budget) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "step_budget must be between 1 and {MAX_STEP_BUDGET}"
+        )));
+    }
+    run_on_robot(&robots, &world, &http, robot_id, |context| {
+        let limits = |instructions| Limits {
+            steps: budget,
+            instructions,
+        };
+        let run = execute(
+            &statements,
+            context.map,
+            context.others,
+            context.start.clone(),
+            limits(None),
+        );
+        let plan = context.plan(&run.instructions);
+        match plan
+            .as_ref()
+            .filter(|plan| plan.steps < run.instructions.len())
+        {
+            // Scripts are deterministic, so the rerun takes the same path up to the cut.
+            Some(plan) => {
+                let limits = limits(Some(plan.steps));
+                let run = execute(
+                    &statements,
+                    context.map,
+                    context.others,
+                    context.start.clone(),
+                    limits,
+                );
+                let plan = context.plan(&run.instructions);
+                (run, plan)
+            }
+            None => (run, plan),
+        }
+    })
+}
+
+/// What a script may sense and where it starts, as of when it was started.
+pub struct ScriptContext<'a> {
+    pub robot_id: RobotId,
+    pub map: &'a WorldMap,
+    pub others: &'a HashSet<Cell>,
+    /// Where the robot is once its queued motion has run.
+    pub start: RobotModel,
+    /// Where the robot is now.
+    pub settled: Pose,
+    pub queued: Vec<char>,
+    pub batteries: Option<&'a Batteries>,
+}
+
+impl ScriptContext<'_> {
+    /// How much of `instructions` the battery powers, when batteries are enabled.
+    pub fn plan(&self, instructions: &[char]) -> Option<Plan> {
+        self.batteries.map(|batteries| {
+            batteries.plan(self.robot_id, self.settled, &self.queued, instructions)
+        })
+    }
+}
+
+/// Run a script front end on the robot, then apply and report what it did.
+///
+/// The script runs without the robots locked, against their state when it started. If the
+/// robot, its queued motion or another robot changed meanwhile nothing is applied and the
+/// answer is `409`. Otherwise the instructions are applied, or queued in simulation mode,
+/// as one move.
+pub fn run_on_robot(
+    robots: &RobotState,
+    world: &World,
+    http: &HttpRequest,
+    robot_id: RobotId,
+    script: impl FnOnce(&ScriptContext) -> (Run, Option<Plan>),
+) -> HttpResponse {
+    let simulation = http.app_data::<web::Data<Simulation>>();
+    let queued_now = || {
+        simulation
+            .map(|simulation| simulation.queued(robot_id))
+            .unwrap_or_default()
+    };
+    let (version, settled, others, queued) = {
+        let robots = robots.lock();
+        let Some(entry) = robots.get(&robot_id) else {
+            return robot_not_found(robot_id);
+        };
+        if let Some(response) = precondition_failed(http, Some(entry)) {
+            return response;
+        }
+        let others = other_robots(&robots, robot_id);
+        (entry.version, entry.robot.clone(), others, queued_now())
+    };
+    let mut start = settled.clone();
+    for &movement in &queued {
+        start.execute(movement);
+    }
+    let batteries = http.app_data::<web::Data<Batteries>>();
+    let map = world.map().clone();
+    let (run, plan) = script(&ScriptContext {
+        robot_id,
+        map: &map,
+        others: &others,
+        start,
+        settled: Pose::from(&settled),
+        queued: queued.clone(),
+        batteries: batteries.map(|batteries| batteries.get_ref()),
+    });
+
+    let mut robots = robots.lock();
+    let others_moved = other_robots(&robots, robot_id) != others;
+    let Some(entry) = robots.get_mut(&robot_id) else {
+        return robot_not_found(robot_id);
+    };
+    if entry.version != version || others_moved || queued_now() != queued {
+        tracing::info!("robots changed while the script ran, nothing applied");
+        return HttpResponse::Conflict()
+            .insert_header(header::ETag(entry.etag()))
+            .json(ErrorResponse::new("robots changed while the script ran"));
+    }
+
+    let executed = run.instructions.len();
+    if let Some(response) = charge_instructions(http, executed, executed) {
+        return response;
+    }
+    if let Some(simulation) = simulation {
+        simulation.enqueue(robot_id, &run.instructions);
+    } else if executed > 0 {
+        let change = Change::Moved {
+            instructions: run.instructions.iter().collect(),
+        };
+        let battery = plan.as_ref().map(|plan| plan.level);
+        if let Some(response) = record_event(http, robot_id, change, battery) {
+            return response;
+        }
+        *entry.update() = run.robot.clone();
+        if let (Some(batteries), Some(plan)) = (batteries, &plan) {
+            batteries.commit(robot_id, plan);
+        }
+        record_executed(http, executed);
+    }
+    tracing::info!(
+        instructions = executed,
+        steps = run.steps,
+        stop = ?run.stop,
+        pose = ?run.robot,
+        "script ran"
+    );
+    HttpResponse::Ok()
+        .insert_header(header::ETag(entry.etag()))
+        .json(ScriptResult {
+            pose: run.robot,
+            instructions: run.instructions.into_iter().collect(),
+            steps: run.steps,
+            stop: run.stop,
+            error: run.error,
+            battery: plan.map(|plan| plan.level),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::RobotState;
+    use crate::pose::Pose;
+    use crate::script::{execute, parse, run_on_robot, run_script, Condition, Limits, Statement};
+    use crate::storage::TempStorage;
+    use crate::world::{Bounds, World, WorldMap, WORLD_FILE};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("while !at(0, -2) { RA } else")
+                .unwrap_err()
+                .to_string(),
+            "unknown instruction 'else' at position 24"
+        );
+        assert_eq!(
+            parse("if facing_north { L } else if front_clear { A }").unwrap(),
+            vec![Statement::If(
+                Condition::FacingNorth,
+                vec![Statement::Move('L')],
+                vec![Statement::If(
+                    Condition::FrontClear,
+                    vec![Statement::Move('A')],
+                    vec![]
+                )]
+            )]
+        );
+        assert!(parse("while front_clear { A").is_err());
+        assert!(parse("A }").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_script_advances_until_blocked() {
+        let storage = TempStorage::new("script");
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: 0,
+                min_y: 0,
+                max_x: 4,
+                max_y: 4,
+            }),
+            ..WorldMap::default()
+        };
+        storage.write_json(WORLD_FILE, &map).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(World::load(storage.clone()).unwrap()))
+                .route("/robots/{id}/script", web::post().to(run_script)),
+        )
+        .await;
+        let script = |body: serde_json::Value| {
+            TestRequest::post()
+                .uri("/robots/0/script")
+                .set_json(body)
+                .to_request()
+        };
+
+        let source = "while front_clear { A } if at(0, 4) { R } else { L }";
+        let result: serde_json::Value =
+            call_and_read_body_json(&app, script(serde_json::json!({ "source": source }))).await;
+        assert_eq!(
+            result["pose"],
+            serde_json::json!({ "x": 0, "y": 4, "facing": "East" })
+        );
+        assert_eq!(result["instructions"], "AAAAR");
+        assert_eq!(result["steps"], 11);
+        assert_eq!(result["stop"], "completed");
+
+        let looping = serde_json::json!({ "source": "while front_clear { }", "step_budget": 5 });
+        let result: serde_json::Value = call_and_read_body_json(&app, script(looping)).await;
+        assert_eq!(result["stop"], "step_budget");
+        assert_eq!(result["instructions"], "");
+        let looping =
+            serde_json::json!({ "source": "while !facing_north { RL }", "step_budget": 5 });
+        let result: serde_json::Value = call_and_read_body_json(&app, script(looping)).await;
+        assert_eq!(result["stop"], "step_budget");
+        assert_eq!(result["steps"], 5);
+
+        let resp = call_service(&app, script(serde_json::json!({ "source": "A B" }))).await;
+        assert_eq!(resp.status(), 400);
+        let nested = "while front_clear { ".repeat(10_000);
+        let resp = call_service(&app, script(serde_json::json!({ "source": nested }))).await;
+        assert_eq!(resp.status(), 400);
+        let negated = format!("if {}front_clear {{ A }}", "!".repeat(100_000));
+        let resp = call_service(&app, script(serde_json::json!({ "source": negated }))).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_script_changes_nothing_when_the_robot_moved_meanwhile() {
+        let storage = TempStorage::new("script");
+        let world = World::load(storage.clone()).unwrap();
+        let robots = RobotState::new();
+        let http = TestRequest::default().to_http_request();
+        let advance = parse("A").unwrap();
+        let run_advance = |turn_meanwhile: bool| {
+            run_on_robot(&robots, &world, &http, 0, |context| {
+                if turn_meanwhile {
+                    robots.lock().get_mut(&0).unwrap().update().execute('R');
+                }
+                let limits = Limits {
+                    steps: 10,
+                    instructions: None,
+                };
+                let run = execute(
+                    &advance,
+                    context.map,
+                    context.others,
+                    context.start.clone(),
+                    limits,
+                );
+                (run, None)
+            })
+        };
+        let pose = || Pose::from(&robots.lock()[&0].robot);
+        let start = pose();
+
+        assert_eq!(run_advance(true).status(), 409);
+        assert_eq!(pose(), start.step('R'));
+        assert_eq!(run_advance(false).status(), 200);
+        assert_eq!(pose(), start.step('R').step('A'));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{robot_not_found, ErrorResponse, RobotId, RobotState, VersionedRobot};
+use crate::pose::Pose;
+use crate::world::{Cell, Reading, World, WorldMap};
+
+/// What a robot perceives from where it stands.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Sensors {
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    pub pose: Pose,
+    pub front: Reading,
+    pub left: Reading,
+    pub right: Reading,
+    /// Cells to the nearest obstacle, robot or arena edge straight ahead, `1` being the next
+    /// cell. Absent when nothing is in the way.
+    pub obstacle_distance: Option<u32>,
+}
+
+/// Cells taken by every robot but `robot_id`.
+pub fn other_robots(robots: &HashMap<RobotId, VersionedRobot>, robot_id: RobotId) -> HashSet<Cell> {
+    robots
+        .iter()
+        .filter(|(id, _)| **id != robot_id)
+        .map(|(_, entry)| Cell::from(Pose::from(&entry.robot)))
+        .collect()
+}
+
+/// Read the robot's sensors against the world map and the other robots.
+pub fn sense(
+    map: &WorldMap,
+    robots: &HashMap<RobotId, VersionedRobot>,
+    robot_id: RobotId,
+) -> Option<Sensors> {
+    let pose = Pose::from(&robots.get(&robot_id)?.robot);
+    let others = other_robots(robots, robot_id);
+    let here = Cell::from(pose);
+    let look = |heading| map.probe(here.towards(heading, 1), &others);
+    Some(Sensors {
+        robot_id,
+        pose,
+        front: look(pose.facing),
+        left: look(pose.facing.left()),
+        right: look(pose.facing.right()),
+        obstacle_distance: map.distance_ahead(here, pose.facing, &others),
+    })
+}
+
+/// What is in front of, left of and right of the robot, and how far it can drive ahead.
+///
+/// In simulation mode this reads from the robot's last settled pose.
+#[utoipa::path(
+    get,
+    path = "/robots/{id}/sensors",
+    params(("id" = u32, Path, description = "Robot id")),
+    responses(
+        (status = 200, description = "Sensor readings", body = Sensors),
+        (status = 404, description = "Unknown robot", body = ErrorResponse)
+    )
+)]
+pub async fn robot_sensors(
+    robots: web::Data<RobotState>,
+    world: web::Data<World>,
+    http: HttpRequest,
+    path: web::Path<RobotId>,
+) -> impl Responder {
+    let robot_id = *path;
+    if let Some(response) = authorize(&http, Permission::Read, robot_id) {
+        return response;
+    }
+    let robots = robots.lock();
+    match sense(&world.map(), &robots, robot_id) {
+        Some(sensors) => HttpResponse::Ok().json(sensors),
+        None => robot_not_found(robot_id),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use actix_web::test::{
+        call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+    };
+    use actix_web::{web, App};
+
+    use crate::controller::{move_robot, reposition_robot, RobotModel, RobotState};
+    use crate::pose::{Heading, Pose};
+    use crate::sensors::robot_sensors;
+    use crate::storage::TempStorage;
+    use crate::world::{Bounds, Cell, World, WorldMap, WORLD_FILE};
+
+    #[test]
+    fn test_distance_ahead_at_the_ends_of_i32() {
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: i32::MIN,
+                min_y: i32::MIN,
+                max_x: i32::MAX,
+                max_y: i32::MAX,
+            }),
+            ..WorldMap::default()
+        };
+        let none = HashSet::new();
+        let from = Cell {
+            x: i32::MAX,
+            y: i32::MAX - 1,
+        };
+        assert_eq!(map.distance_ahead(from, Heading::North, &none), Some(2));
+        assert_eq!(map.distance_ahead(from, Heading::East, &none), Some(1));
+        assert_eq!(
+            map.distance_ahead(from, Heading::West, &none),
+            Some(u32::MAX)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_moves_stop_at_the_ends_of_i32() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/reposition_robot")
+            .set_json(serde_json::json!({ "x": i32::MAX, "y": 0, "facing": "East" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "A" }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["steps"], 0);
+        assert_eq!(body["pose"]["x"], i32::MAX);
+
+        // The engine saturates too, should anything drive it past the check.
+        let mut robot = RobotModel::from(Pose {
+            x: i32::MIN,
+            y: 0,
+            facing: Heading::West,
+        });
+        robot.execute('A');
+        assert_eq!(Pose::from(&robot).x, i32::MIN);
+    }
+
+    #[actix_web::test]
+    async fn test_sensors_read_the_world() {
+        let storage = TempStorage::new("sensors");
+        let map = WorldMap {
+            bounds: Some(Bounds {
+                min_x: 0,
+                min_y: 0,
+                max_x: 9,
+                max_y: 9,
+            }),
+            obstacles: [Cell { x: 0, y: 4 }].into(),
+            ..WorldMap::default()
+        };
+        storage.write_json(WORLD_FILE, &map).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RobotState::new()))
+                .app_data(web::Data::new(World::load(storage.clone()).unwrap()))
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot))
+                .route("/robots/{id}/sensors", web::get().to(robot_sensors)),
+        )
+        .await;
+        let sensors = |id: u32| {
+            TestRequest::get()
+                .uri(&format!("/robots/{id}/sensors"))
+                .to_request()
+        };
+
+        let reading: serde_json::Value = call_and_read_body_json(&app, sensors(0)).await;
+        assert_eq!(reading["front"], "free");
+        assert_eq!(reading["left"], "edge");
+        assert_eq!(reading["right"], "free");
+        assert_eq!(reading["obstacle_distance"], 4);
+
+        let req = TestRequest::post()
+            .uri("/reposition_robot?robot_id=1")
+            .set_json(serde_json::json!({ "x": 1, "y": 0, "facing": "East" }))
+            .to_request();
+        call_service(&app, req).await;
+        let reading: serde_json::Value = call_and_read_body_json(&app, sensors(0)).await;
+        assert_eq!(reading["right"], "robot");
+        let reading: serde_json::Value = call_and_read_body_json(&app, sensors(1)).await;
+        assert_eq!(reading["front"], "free");
+        assert_eq!(reading["right"], "edge");
+        assert_eq!(reading["obstacle_distance"], 9);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "AAAAR" }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        let collision: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(collision["steps"], 3);
+        assert_eq!(collision["reading"], "obstacle");
+        assert_eq!(collision["cell"], serde_json::json!({ "x": 0, "y": 4 }));
+        let reading: serde_json::Value = call_and_read_body_json(&app, sensors(0)).await;
+        assert_eq!(reading["front"], "obstacle");
+        assert_eq!(reading["pose"]["y"], 3);
+
+        assert_eq!(call_service(&app, sensors(7)).await.status(), 404);
+    }
+}
@@ -0,0 +1,101 @@
+use std::process::ExitCode;
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+
+use crate::battery::Batteries;
+use crate::controller::RobotState;
+use crate::events::EventLog;
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::simulation::Simulation;
+
+/// Jobs were still running when the drain timeout expired and had to be cancelled.
+const EXIT_JOBS_CANCELLED: u8 = 2;
+/// The final snapshot could not be written.
+const EXIT_SNAPSHOT_FAILED: u8 = 3;
+
+/// `ROBOT_SHUTDOWN_TIMEOUT_SECS`, defaulting to 30 seconds.
+pub fn timeout_from_env() -> Duration {
+    let seconds = std::env::var("ROBOT_SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+/// Resolve on the first SIGINT or SIGTERM, returning the signal name.
+async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+/// Wait for a signal, then stop accepting connections, drain jobs and stop the server
+/// once in-flight requests complete. Returns the number of jobs cancelled.
+pub async fn stop_on_signal(
+    server: ServerHandle,
+    jobs: web::Data<Jobs>,
+    timeout: Duration,
+) -> usize {
+    let signal = signal().await;
+    tracing::info!(signal, timeout_secs = timeout.as_secs(), "shutting down");
+    server.pause().await;
+    let cancelled = jobs.drain(timeout).await;
+    if cancelled > 0 {
+        tracing::warn!(
+            cancelled,
+            "jobs still running at the timeout were cancelled"
+        );
+    }
+    server.stop(true).await;
+    cancelled
+}
+
+/// Carry out the motion still queued in simulation mode, so instructions clients were told
+/// are accepted get logged and land in the final snapshot.
+pub fn settle_motion(
+    simulation: &Simulation,
+    robots: &RobotState,
+    log: &EventLog,
+    batteries: Option<&Batteries>,
+    metrics: Option<&Metrics>,
+) {
+    let moving = simulation.settle(robots, Some(log), batteries, metrics);
+    if moving > 0 {
+        tracing::info!(robots = moving, "queued motion applied before shutdown");
+    }
+}
+
+/// Save the robots to the data directory and pick the process exit code.
+pub fn finish(log: &EventLog, cancelled_jobs: usize) -> ExitCode {
+    let snapshot = match log.snapshot() {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            tracing::error!(%err, "final snapshot failed");
+            return ExitCode::from(EXIT_SNAPSHOT_FAILED);
+        }
+    };
+    tracing::info!(
+        robots = snapshot.robots.len(),
+        seq = snapshot.seq,
+        "final snapshot written"
+    );
+    if cancelled_jobs > 0 {
+        return ExitCode::from(EXIT_JOBS_CANCELLED);
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,914 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{authorize, Permission};
+use crate::battery::Batteries;
+use crate::controller::{ErrorResponse, RobotId, RobotModel, RobotState, DEFAULT_ROBOT};
+use crate::events::{Change, EventLog};
+use crate::metrics::Metrics;
+use crate::pose::{Heading, Pose};
+
+/// Source of simulated time, injectable so tests can step it by hand.
+pub trait Clock: Send + Sync {
+    /// Simulated time elapsed since the clock started.
+    fn now(&self) -> Duration;
+}
+
+/// Simulated time runs with the wall clock.
+pub struct SystemClock {
+    started: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+#[derive(Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+struct ClockState {
+    /// Simulated time at the last pause, resume or speed change.
+    base: Duration,
+    /// Source time at the last change.
+    anchor: Duration,
+    speed: f64,
+    paused: bool,
+}
+
+/// Simulated time derived from a source clock, which can be paused, stepped and sped up.
+struct ControlledClock {
+    source: Arc<dyn Clock>,
+    state: Mutex<ClockState>,
+}
+
+impl ControlledClock {
+    fn new(source: Arc<dyn Clock>, speed: f64) -> Self {
+        ControlledClock {
+            state: Mutex::new(ClockState {
+                base: Duration::ZERO,
+                anchor: source.now(),
+                speed,
+                paused: false,
+            }),
+            source,
+        }
+    }
+
+    fn at(state: &ClockState, source: Duration) -> Duration {
+        if state.paused {
+            return state.base;
+        }
+        state.base + source.saturating_sub(state.anchor).mul_f64(state.speed)
+    }
+
+    /// Change the clock, starting a new segment at the current simulated time.
+    fn update(&self, f: impl FnOnce(&mut ClockState)) {
+        let mut state = self.state.lock().unwrap();
+        let source = self.source.now();
+        state.base = Self::at(&state, source);
+        state.anchor = source;
+        f(&mut state);
+    }
+}
+
+impl Clock for ControlledClock {
+    fn now(&self) -> Duration {
+        Self::at(&self.state.lock().unwrap(), self.source.now())
+    }
+}
+
+#[derive(Clone)]
+pub struct SimulationConfig {
+    /// Time one `A` takes.
+    pub advance: Duration,
+    /// Time one `L` or `R` takes.
+    pub turn: Duration,
+    /// Wall-clock interval of the background loop, and the simulated time of one step.
+    pub tick: Duration,
+    /// Simulated seconds per wall-clock second.
+    pub speed: f64,
+}
+
+impl SimulationConfig {
+    /// Enabled by `ROBOT_SIMULATION=1`, with durations from `ROBOT_SIM_ADVANCE_MS`
+    /// (default 1000), `ROBOT_SIM_TURN_MS` (default 500) and `ROBOT_SIM_TICK_MS` (default 50),
+    /// and the starting speed from `ROBOT_SIM_SPEED` (default 1).
+    ///
+    /// A zero tick or a speed the clock does not accept is an error.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        if std::env::var("ROBOT_SIMULATION").ok().as_deref() != Some("1") {
+            return Ok(None);
+        }
+        let millis = |name: &str, default: u64| {
+            Duration::from_millis(
+                std::env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+        let config = SimulationConfig {
+            advance: millis("ROBOT_SIM_ADVANCE_MS", 1000),
+            turn: millis("ROBOT_SIM_TURN_MS", 500),
+            tick: millis("ROBOT_SIM_TICK_MS", 50),
+            speed: std::env::var("ROBOT_SIM_SPEED")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1.0),
+        };
+        config
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(Some(config))
+    }
+
+    /// Check the tick can drive the background loop and the clock accepts the speed.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tick.is_zero() {
+            return Err("ROBOT_SIM_TICK_MS must be at least 1".to_string());
+        }
+        if !valid_speed(self.speed) {
+            let (min, max) = SPEED_RANGE;
+            return Err(format!("ROBOT_SIM_SPEED must be between {min} and {max}"));
+        }
+        Ok(())
+    }
+
+    fn duration(&self, instruction: char) -> Duration {
+        match instruction {
+            'A' => self.advance,
+            _ => self.turn,
+        }
+    }
+}
+
+/// Instructions a robot still has to carry out.
+struct Motion {
+    queue: VecDeque<char>,
+    /// When the instruction at the head of the queue started.
+    started: Duration,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MotionStatus {
+    #[schema(value_type = u32)]
+    pub robot_id: RobotId,
+    /// Instructions waiting, including the one in progress.
+    pub queued: usize,
+    /// Simulated milliseconds until the robot stops.
+    pub finishes_in_ms: u64,
+}
+
+/// Where a robot is at the current simulated time, part way through an instruction.
+#[derive(Serialize, ToSchema)]
+pub struct InterpolatedPose {
+    pub x: f64,
+    pub y: f64,
+    /// Degrees clockwise from north.
+    pub heading: f64,
+    /// The last pose the robot fully reached.
+    pub settled: Pose,
+    /// Whether an instruction is in progress.
+    pub moving: bool,
+    pub queued: usize,
+    pub sim_time_ms: u64,
+}
+
+fn degrees(heading: Heading) -> f64 {
+    match heading {
+        Heading::North => 0.0,
+        Heading::East => 90.0,
+        Heading::South => 180.0,
+        Heading::West => 270.0,
+    }
+}
+
+pub struct Simulation {
+    config: SimulationConfig,
+    clock: ControlledClock,
+    motions: Mutex<HashMap<RobotId, Motion>>,
+}
+
+impl Simulation {
+    /// Run simulated time off `source`, starting at the configured speed.
+    pub fn new(config: SimulationConfig, source: Arc<dyn Clock>) -> Self {
+        Simulation {
+            clock: ControlledClock::new(source, config.speed),
+            config,
+            motions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn status(&self) -> ClockStatus {
+        let state = self.clock.state.lock().unwrap();
+        ClockStatus {
+            sim_time_ms: ControlledClock::at(&state, self.clock.source.now()).as_millis() as u64,
+            paused: state.paused,
+            speed: state.speed,
+            tick_ms: self.config.tick.as_millis() as u64,
+        }
+    }
+
+    fn finishes_in(&self, motion: &Motion, now: Duration) -> Duration {
+        let total: Duration = motion
+            .queue
+            .iter()
+            .map(|&instruction| self.config.duration(instruction))
+            .sum();
+        (motion.started + total).saturating_sub(now)
+    }
+
+    /// Queue instructions behind whatever the robot is already doing.
+    pub fn enqueue(&self, robot_id: RobotId, program: &[char]) -> MotionStatus {
+        let now = self.clock.now();
+        let mut motions = self.motions.lock().unwrap();
+        let motion = motions.entry(robot_id).or_insert_with(|| Motion {
+            queue: VecDeque::new(),
+            started: now,
+        });
+        if motion.queue.is_empty() {
+            motion.started = now;
+        }
+        motion.queue.extend(program);
+        MotionStatus {
+            robot_id,
+            queued: motion.queue.len(),
+            finishes_in_ms: self.finishes_in(motion, now).as_millis() as u64,
+        }
+    }
+
+    /// Instructions still to run, in order.
+    pub fn queued(&self, robot_id: RobotId) -> Vec<char> {
+        self.motions
+            .lock()
+            .unwrap()
+            .get(&robot_id)
+            .map(|motion| motion.queue.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drop a robot's pending instructions, e.g. because it was repositioned.
+    pub fn cancel(&self, robot_id: RobotId) {
+        self.motions.lock().unwrap().remove(&robot_id);
+    }
+
+    /// Apply every instruction that has finished by now, logging them as moves and counting
+    /// them as executed.
+    ///
+    /// A robot whose battery runs out stops and its remaining motion is dropped.
+    pub fn tick(
+        &self,
+        robots: &RobotState,
+        events: Option<&EventLog>,
+        batteries: Option<&Batteries>,
+        metrics: Option<&Metrics>,
+    ) {
+        self.apply_until(self.clock.now(), robots, events, batteries, metrics);
+    }
+
+    /// Apply all queued instructions at once, as at shutdown, where clients have already
+    /// been told they were accepted. Returns how many robots were still moving.
+    pub fn settle(
+        &self,
+        robots: &RobotState,
+        events: Option<&EventLog>,
+        batteries: Option<&Batteries>,
+        metrics: Option<&Metrics>,
+    ) -> usize {
+        let moving = self.motions.lock().unwrap().len();
+        self.apply_until(Duration::MAX, robots, events, batteries, metrics);
+        moving
+    }
+
+    fn apply_until(
+        &self,
+        now: Duration,
+        robots: &RobotState,
+        events: Option<&EventLog>,
+        batteries: Option<&Batteries>,
+        metrics: Option<&Metrics>,
+    ) {
+        let mut robots = robots.lock();
+        let mut motions = self.motions.lock().unwrap();
+        motions.retain(|&robot_id, motion| {
+            let Some(entry) = robots.get_mut(&robot_id) else {
+                return false;
+            };
+            let mut finished = String::new();
+            let mut started = motion.started;
+            for &instruction in &motion.queue {
+                let duration = self.config.duration(instruction);
+                if started.saturating_add(duration) > now {
+                    break;
+                }
+                started = started.saturating_add(duration);
+                finished.push(instruction);
+            }
+            if finished.is_empty() {
+                return true;
+            }
+            let plan = batteries.map(|batteries| {
+                let program: Vec<char> = finished.chars().collect();
+                batteries.plan(robot_id, Pose::from(&entry.robot), &[], &program)
+            });
+            let depleted = plan
+                .as_ref()
+                .is_some_and(|plan| plan.steps < finished.len());
+            if let Some(plan) = plan.as_ref().filter(|_| depleted) {
+                tracing::warn!(
+                    robot_id,
+                    steps = plan.steps,
+                    dropped = motion.queue.len() - plan.steps,
+                    "battery ran out, motion stopped"
+                );
+                finished.truncate(plan.steps);
+            }
+            if let Some(events) = events.filter(|_| !finished.is_empty()) {
+                let change = Change::Moved {
+                    instructions: finished.clone(),
+                };
+                let battery = plan.as_ref().map(|plan| plan.level);
+                if let Err(err) = events.append(robot_id, change, battery) {
+                    tracing::error!(%err, robot_id, "event log write failed, motion held");
+                    return true;
+                }
+            }
+            let robot = entry.update();
+            for instruction in finished.chars() {
+                robot.execute(instruction);
+            }
+            if let (Some(batteries), Some(plan)) = (batteries, &plan) {
+                batteries.commit(robot_id, plan);
+            }
+            if let Some(metrics) = metrics {
+                metrics.executed(finished.len());
+            }
+            motion.queue.drain(..finished.len());
+            motion.started = started;
+            !depleted && !motion.queue.is_empty()
+        });
+    }
+
+    /// The robot's pose at the current simulated time, given its last settled pose.
+    pub fn interpolate(&self, robot_id: RobotId, settled: &RobotModel) -> InterpolatedPose {
+        let now = self.clock.now();
+        let mut robot = settled.clone();
+        let mut in_progress = None;
+        let mut queued = 0;
+        if let Some(motion) = self.motions.lock().unwrap().get(&robot_id) {
+            // The loop may lag behind the clock, so finished instructions are applied here too.
+            let mut started = motion.started;
+            for (done, &instruction) in motion.queue.iter().enumerate() {
+                let duration = self.config.duration(instruction);
+                if started + duration > now {
+                    let fraction = (now - started).as_secs_f64() / duration.as_secs_f64();
+                    in_progress = Some((instruction, fraction));
+                    queued = motion.queue.len() - done;
+                    break;
+                }
+                robot.execute(instruction);
+                started += duration;
+            }
+        }
+
+        let pose = Pose::from(&robot);
+        let (mut x, mut y, mut heading) =
+            (f64::from(pose.x), f64::from(pose.y), degrees(pose.facing));
+        match in_progress {
+            Some(('A', fraction)) => {
+                let (dx, dy) = pose.facing.offset();
+                x += f64::from(dx) * fraction;
+                y += f64::from(dy) * fraction;
+            }
+            Some(('L', fraction)) => heading = (heading - 90.0 * fraction).rem_euclid(360.0),
+            Some((_, fraction)) => heading = (heading + 90.0 * fraction).rem_euclid(360.0),
+            None => (),
+        }
+        InterpolatedPose {
+            x,
+            y,
+            heading,
+            settled: pose,
+            moving: in_progress.is_some(),
+            queued,
+            sim_time_ms: now.as_millis() as u64,
+        }
+    }
+}
+
+/// Apply finished instructions every tick until the server stops.
+pub async fn run(
+    simulation: web::Data<Simulation>,
+    robots: web::Data<RobotState>,
+    events: Option<web::Data<EventLog>>,
+    batteries: Option<web::Data<Batteries>>,
+    metrics: Option<web::Data<Metrics>>,
+) {
+    let mut interval = tokio::time::interval(simulation.config.tick);
+    loop {
+        interval.tick().await;
+        simulation.tick(
+            &robots,
+            events.as_ref().map(|events| events.get_ref()),
+            batteries.as_ref().map(|batteries| batteries.get_ref()),
+            metrics.as_ref().map(|metrics| metrics.get_ref()),
+        );
+    }
+}
+
+/// Slowest and fastest speed the clock accepts.
+const SPEED_RANGE: (f64, f64) = (0.001, 1000.0);
+
+fn valid_speed(speed: f64) -> bool {
+    (SPEED_RANGE.0..=SPEED_RANGE.1).contains(&speed)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ClockStatus {
+    pub sim_time_ms: u64,
+    pub paused: bool,
+    /// Simulated seconds per wall-clock second.
+    pub speed: f64,
+    /// Simulated milliseconds one step advances.
+    pub tick_ms: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SpeedChange {
+    pub speed: f64,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct StepCount {
+    /// Ticks to advance, defaults to one.
+    pub ticks: Option<u32>,
+}
+
+fn simulation_off() -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(
+        "simulation mode is off, start the server with ROBOT_SIMULATION=1",
+    ))
+}
+
+/// Simulated time, whether it is paused and how fast it runs.
+#[utoipa::path(
+    get,
+    path = "/simulation/clock",
+    responses(
+        (status = 200, description = "Clock state", body = ClockStatus),
+        (status = 404, description = "Simulation mode is off", body = ErrorResponse)
+    )
+)]
+pub async fn clock_status(
+    simulation: Option<web::Data<Simulation>>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    match simulation {
+        Some(simulation) => HttpResponse::Ok().json(simulation.status()),
+        None => simulation_off(),
+    }
+}
+
+/// Freeze simulated time. Robots stop where they are and keep their queues.
+#[utoipa::path(
+    post,
+    path = "/simulation/pause",
+    responses(
+        (status = 200, description = "Clock paused", body = ClockStatus),
+        (status = 404, description = "Simulation mode is off", body = ErrorResponse)
+    )
+)]
+pub async fn pause_clock(
+    simulation: Option<web::Data<Simulation>>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::ControlClock, DEFAULT_ROBOT) {
+        return response;
+    }
+    let Some(simulation) = simulation else {
+        return simulation_off();
+    };
+    simulation.clock.update(|state| state.paused = true);
+    tracing::info!("simulation paused");
+    HttpResponse::Ok().json(simulation.status())
+}
+
+/// Let simulated time run again.
+#[utoipa::path(
+    post,
+    path = "/simulation/resume",
+    responses(
+        (status = 200, description = "Clock running", body = ClockStatus),
+        (status = 404, description = "Simulation mode is off", body = ErrorResponse)
+    )
+)]
+pub async fn resume_clock(
+    simulation: Option<web::Data<Simulation>>,
+    http: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::ControlClock, DEFAULT_ROBOT) {
+        return response;
+    }
+    let Some(simulation) = simulation else {
+        return simulation_off();
+    };
+    simulation.clock.update(|state| state.paused = false);
+    tracing::info!("simulation resumed");
+    HttpResponse::Ok().json(simulation.status())
+}
+
+/// Advance simulated time by whole ticks and apply what finished, also while paused.
+#[utoipa::path(
+    post,
+    path = "/simulation/step",
+    params(StepCount),
+    responses(
+        (status = 200, description = "Clock after the step", body = ClockStatus),
+        (status = 404, description = "Simulation mode is off", body = ErrorResponse)
+    )
+)]
+pub async fn step_clock(
+    simulation: Option<web::Data<Simulation>>,
+    robots: web::Data<RobotState>,
+    http: HttpRequest,
+    query: web::Query<StepCount>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::ControlClock, DEFAULT_ROBOT) {
+        return response;
+    }
+    let Some(simulation) = simulation else {
+        return simulation_off();
+    };
+    let ticks = query.ticks.unwrap_or(1);
+    let by = simulation.config.tick * ticks;
+    simulation.clock.update(|state| state.base += by);
+    let events = http.app_data::<web::Data<EventLog>>();
+    let batteries = http.app_data::<web::Data<Batteries>>();
+    let metrics = http.app_data::<web::Data<Metrics>>();
+    simulation.tick(
+        &robots,
+        events.map(|events| events.get_ref()),
+        batteries.map(|batteries| batteries.get_ref()),
+        metrics.map(|metrics| metrics.get_ref()),
+    );
+    tracing::info!(ticks, "simulation stepped");
+    HttpResponse::Ok().json(simulation.status())
+}
+
+/// Change how many simulated seconds pass per wall-clock second.
+#[utoipa::path(
+    put,
+    path = "/simulation/speed",
+    request_body = SpeedChange,
+    responses(
+        (status = 200, description = "Clock at the new speed", body = ClockStatus),
+        (status = 400, description = "Speed out of range", body = ErrorResponse),
+        (status = 404, description = "Simulation mode is off", body = ErrorResponse)
+    )
+)]
+pub async fn set_speed(
+    simulation: Option<web::Data<Simulation>>,
+    http: HttpRequest,
+    req: web::Json<SpeedChange>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::ControlClock, DEFAULT_ROBOT) {
+        return response;
+    }
+    let Some(simulation) = simulation else {
+        return simulation_off();
+    };
+    if !valid_speed(req.speed) {
+        let (min, max) = SPEED_RANGE;
+        return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "speed must be between {min} and {max}"
+        )));
+    }
+    simulation.clock.update(|state| state.speed = req.speed);
+    tracing::info!(speed = req.speed, "simulation speed changed");
+    HttpResponse::Ok().json(simulation.status())
+}
+
+/// Drop the robot's pending instructions when the simulation is running.
+pub fn cancel_motion(req: &HttpRequest, robot_id: RobotId) {
+    if let Some(simulation) = req.app_data::<web::Data<Simulation>>() {
+        simulation.cancel(robot_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use crate::controller::{move_robot, reposition_robot, robot_position, RobotState};
+    use crate::events::{Change, EventLog};
+    use crate::metrics::Metrics;
+    use crate::pose::Pose;
+    use crate::simulation::{
+        clock_status, pause_clock, resume_clock, set_speed, step_clock, ManualClock, Simulation,
+        SimulationConfig,
+    };
+    use crate::storage::TempStorage;
+
+    #[test]
+    fn test_config_rejects_zero_tick_and_bad_speed() {
+        let config = SimulationConfig {
+            advance: Duration::from_secs(1),
+            turn: Duration::from_millis(500),
+            tick: Duration::from_millis(50),
+            speed: 1.0,
+        };
+        assert_eq!(config.validate(), Ok(()));
+        let zero_tick = SimulationConfig {
+            tick: Duration::ZERO,
+            ..config.clone()
+        };
+        assert!(zero_tick.validate().is_err());
+        for speed in [0.0, -1.0, f64::NAN] {
+            let config = SimulationConfig {
+                speed,
+                ..config.clone()
+            };
+            assert!(config.validate().is_err(), "{speed}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_motion_follows_the_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let config = SimulationConfig {
+            advance: Duration::from_secs(1),
+            turn: Duration::from_millis(500),
+            tick: Duration::from_millis(50),
+            speed: 1.0,
+        };
+        let simulation = web::Data::new(Simulation::new(config, clock.clone()));
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(simulation.clone())
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/reposition_robot", web::post().to(reposition_robot))
+                .route("/robot_position", web::get().to(robot_position)),
+        )
+        .await;
+        let position = || TestRequest::get().uri("/robot_position").to_request();
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "RAA" }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 202);
+
+        clock.advance(Duration::from_millis(250));
+        let pose: serde_json::Value = call_and_read_body_json(&app, position()).await;
+        assert_eq!(pose["heading"], 45.0);
+        assert_eq!(pose["queued"], 3);
+
+        clock.advance(Duration::from_millis(750));
+        let pose: serde_json::Value = call_and_read_body_json(&app, position()).await;
+        assert_eq!(
+            (pose["x"].as_f64(), pose["y"].as_f64()),
+            (Some(0.5), Some(0.0))
+        );
+        assert_eq!(pose["heading"], 90.0);
+        assert_eq!(pose["moving"], true);
+
+        simulation.tick(&robots, None, None, None);
+        assert_eq!(
+            Pose::from(&robots.lock()[&0].robot),
+            serde_json::from_value(serde_json::json!({ "x": 0, "y": 0, "facing": "East" }))
+                .unwrap()
+        );
+
+        clock.advance(Duration::from_secs(5));
+        simulation.tick(&robots, None, None, None);
+        let pose: serde_json::Value = call_and_read_body_json(&app, position()).await;
+        assert_eq!(
+            pose["settled"],
+            serde_json::json!({ "x": 2, "y": 0, "facing": "East" })
+        );
+        assert_eq!(pose["moving"], false);
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "AAAA" }))
+            .to_request();
+        call_service(&app, req).await;
+        let req = TestRequest::post()
+            .uri("/reposition_robot")
+            .set_json(serde_json::json!({ "x": 7, "y": 3, "facing": "North" }))
+            .to_request();
+        call_service(&app, req).await;
+        clock.advance(Duration::from_secs(10));
+        simulation.tick(&robots, None, None, None);
+        let pose: serde_json::Value = call_and_read_body_json(&app, position()).await;
+        assert_eq!(
+            pose["settled"],
+            serde_json::json!({ "x": 7, "y": 3, "facing": "North" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_settle_applies_and_logs_queued_motion() {
+        let storage = TempStorage::new("simulation");
+        let log = web::Data::new(EventLog::open(&storage).unwrap());
+        let config = SimulationConfig {
+            advance: Duration::from_secs(1),
+            turn: Duration::from_millis(500),
+            tick: Duration::from_millis(50),
+            speed: 1.0,
+        };
+        let clock = Arc::new(ManualClock::default());
+        let simulation = web::Data::new(Simulation::new(config, clock.clone()));
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(log.clone())
+                .app_data(simulation.clone())
+                .route("/move_robot", web::post().to(move_robot)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "RAA" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 202);
+
+        clock.advance(Duration::from_millis(500));
+        simulation.tick(&robots, Some(&log), None, None);
+        assert_eq!(simulation.settle(&robots, Some(&log), None, None), 1);
+        let settled = serde_json::json!({ "x": 2, "y": 0, "facing": "East" });
+        assert_eq!(
+            Pose::from(&robots.lock()[&0].robot),
+            serde_json::from_value(settled).unwrap()
+        );
+        assert!(simulation.queued(0).is_empty());
+        let logged: Vec<Change> = log
+            .events(Some(0), 0)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.change)
+            .collect();
+        let moved = |instructions: &str| Change::Moved {
+            instructions: instructions.to_string(),
+        };
+        assert_eq!(logged, [moved("R"), moved("AA")]);
+        assert_eq!(log.robots()[&0].robot, robots.lock()[&0].robot);
+    }
+
+    #[actix_web::test]
+    async fn test_instructions_count_as_executed_when_applied() {
+        let config = SimulationConfig {
+            advance: Duration::from_secs(1),
+            turn: Duration::from_millis(500),
+            tick: Duration::from_millis(50),
+            speed: 1.0,
+        };
+        let clock = Arc::new(ManualClock::default());
+        let simulation = web::Data::new(Simulation::new(config, clock.clone()));
+        let robots = web::Data::new(RobotState::new());
+        let metrics = web::Data::new(Metrics::default());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(simulation.clone())
+                .app_data(metrics.clone())
+                .route("/move_robot", web::post().to(move_robot)),
+        )
+        .await;
+        let executed = || {
+            metrics
+                .render(&robots)
+                .lines()
+                .find_map(|line| line.strip_prefix("robot_instructions_executed_total{"))
+                .and_then(|line| line.rsplit(' ').next())
+                .map(|count| count.to_string())
+        };
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "RAA" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 202);
+        assert_eq!(executed().as_deref(), Some("0"));
+
+        clock.advance(Duration::from_millis(500));
+        simulation.tick(&robots, None, None, Some(&metrics));
+        assert_eq!(executed().as_deref(), Some("1"));
+
+        simulation.settle(&robots, None, None, Some(&metrics));
+        assert_eq!(executed().as_deref(), Some("3"));
+    }
+
+    #[actix_web::test]
+    async fn test_pause_step_and_speed() {
+        let clock = Arc::new(ManualClock::default());
+        let config = SimulationConfig {
+            advance: Duration::from_millis(200),
+            turn: Duration::from_millis(100),
+            tick: Duration::from_millis(100),
+            speed: 1.0,
+        };
+        let simulation = web::Data::new(Simulation::new(config, clock.clone()));
+        let robots = web::Data::new(RobotState::new());
+        let app = init_service(
+            App::new()
+                .app_data(robots.clone())
+                .app_data(simulation.clone())
+                .route("/move_robot", web::post().to(move_robot))
+                .route("/simulation/clock", web::get().to(clock_status))
+                .route("/simulation/pause", web::post().to(pause_clock))
+                .route("/simulation/resume", web::post().to(resume_clock))
+                .route("/simulation/step", web::post().to(step_clock))
+                .route("/simulation/speed", web::put().to(set_speed)),
+        )
+        .await;
+        let post = |uri: &str| TestRequest::post().uri(uri).to_request();
+
+        let req = TestRequest::post()
+            .uri("/move_robot")
+            .set_json(serde_json::json!({ "instructions": "AAAA" }))
+            .to_request();
+        call_service(&app, req).await;
+
+        let status: serde_json::Value =
+            call_and_read_body_json(&app, post("/simulation/pause")).await;
+        assert_eq!(status["paused"], true);
+        clock.advance(Duration::from_secs(60));
+        simulation.tick(&robots, None, None, None);
+        assert_eq!(Pose::from(&robots.lock()[&0].robot).y, 0);
+
+        let status: serde_json::Value =
+            call_and_read_body_json(&app, post("/simulation/step?ticks=4")).await;
+        assert_eq!(status["sim_time_ms"], 400);
+        assert_eq!(Pose::from(&robots.lock()[&0].robot).y, 2);
+
+        let req = TestRequest::put()
+            .uri("/simulation/speed")
+            .set_json(serde_json::json!({ "speed": 0.0 }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+        let req = TestRequest::put()
+            .uri("/simulation/speed")
+            .set_json(serde_json::json!({ "speed": 2.0 }))
+            .to_request();
+        call_service(&app, req).await;
+        call_service(&app, post("/simulation/resume")).await;
+        clock.advance(Duration::from_millis(100));
+        let req = TestRequest::get().uri("/simulation/clock").to_request();
+        let status: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(status["sim_time_ms"], 600);
+        assert_eq!(status["speed"], 2.0);
+        simulation.tick(&robots, None, None, None);
+        assert_eq!(Pose::from(&robots.lock()[&0].robot).y, 3);
+    }
+}
@@ -1,17 +1,11 @@
 // This module acts as a facade that re-exports the appropriate types
 // based on which feature is enabled
 
-#[cfg(feature = "command")]
-mod command_pattern;
-
-#[cfg(feature = "no_pattern")]
-mod no_pattern;
-
-#[cfg(feature = "state")]
-mod state_pattern;
-
-#[cfg(feature = "type_state")]
-mod type_state_pattern;
+// Every solution is compiled so that robot-cli can pick one at run time
+pub mod command_pattern;
+pub mod no_pattern;
+pub mod state_pattern;
+pub mod type_state_pattern;
 
 #[cfg(feature = "command")]
 mod solution {
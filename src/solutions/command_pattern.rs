@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Direction {
     North,
     East,
@@ -38,10 +38,10 @@ impl Robot {
 
     fn advance(&mut self) {
         match self.facing {
-            Direction::North => self.y += 1,
-            Direction::East => self.x += 1,
-            Direction::South => self.y -= 1,
-            Direction::West => self.x -= 1,
+            Direction::North => self.y = self.y.saturating_add(1),
+            Direction::East => self.x = self.x.saturating_add(1),
+            Direction::South => self.y = self.y.saturating_sub(1),
+            Direction::West => self.x = self.x.saturating_sub(1),
         }
     }
 }
@@ -93,13 +93,13 @@ impl Command for AdvanceCommand {
 }
 
 // Command invoker that maintains history
-struct RobotController {
+pub struct RobotController {
     history: Vec<Box<dyn Command>>,
     robot: Robot,
 }
 
 impl RobotController {
-    fn new(x: i32, y: i32, facing: Direction) -> Self {
+    pub fn new(x: i32, y: i32, facing: Direction) -> Self {
         Self {
             history: Vec::new(),
             robot: Robot::new(x, y, facing),
@@ -111,28 +111,28 @@ impl RobotController {
         self.history.push(command);
     }
 
-    fn undo_last(&mut self) {
+    pub fn undo_last(&mut self) {
         if let Some(command) = self.history.pop() {
             command.undo(&mut self.robot);
         }
     }
 
-    fn process_instruction(&mut self, instruction: char) {
+    pub fn process_instruction(&mut self, instruction: char) {
         match instruction {
             'L' => self.execute(Box::new(TurnLeftCommand)),
             'R' => self.execute(Box::new(TurnRightCommand)),
             'A' => self.execute(Box::new(AdvanceCommand)),
-            _ => println!("Unknown command: {}", instruction),
+            _ => tracing::warn!(%instruction, "Unknown command"),
         }
     }
 
-    fn position(&self) -> (i32, i32, &Direction) {
+    pub fn position(&self) -> (i32, i32, &Direction) {
         (self.robot.x, self.robot.y, &self.robot.facing)
     }
 }
 
 pub fn run() {
-    println!("Running Command Pattern Solution!");
+    tracing::info!("Running Command Pattern Solution!");
 }
 
 #[cfg(test)]
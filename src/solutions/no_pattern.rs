@@ -8,7 +8,7 @@ pub enum Direction {
     South,
     West,
 }
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Robot {
     pub x: i32,
     pub y: i32,
@@ -49,16 +49,22 @@ impl Robot {
 
     pub fn advance(&mut self) {
         (self.x, self.y) = match self.facing {
-            Direction::North => (self.x, self.y + 1),
-            Direction::East => (self.x + 1, self.y),
-            Direction::South => (self.x, self.y - 1),
-            Direction::West => (self.x - 1, self.y),
+            Direction::North => (self.x, self.y.saturating_add(1)),
+            Direction::East => (self.x.saturating_add(1), self.y),
+            Direction::South => (self.x, self.y.saturating_sub(1)),
+            Direction::West => (self.x.saturating_sub(1), self.y),
         }
     }
 }
 
+impl Default for Robot {
+    fn default() -> Self {
+        Robot::new(0, 0, Direction::North)
+    }
+}
+
 pub fn run() {
-    println!("Running Default (Non-Pattern) Solution!");
+    tracing::info!("Running Default (Non-Pattern) Solution!");
 }
 
 #[cfg(test)]
@@ -1,7 +1,8 @@
+use std::fmt;
 use std::sync::Arc;
 
 // Direction trait defining behavior for each state
-trait Direction {
+pub trait Direction {
     fn turn_right(self: Arc<Self>) -> Arc<dyn Direction>;
     fn turn_left(self: Arc<Self>) -> Arc<dyn Direction>;
     fn advance(&self, x: i32, y: i32) -> (i32, i32);
@@ -9,10 +10,33 @@ trait Direction {
 }
 
 // Concrete direction states
-struct North;
-struct East;
-struct South;
-struct West;
+#[derive(Debug)]
+pub struct North;
+#[derive(Debug)]
+pub struct East;
+#[derive(Debug)]
+pub struct South;
+#[derive(Debug)]
+pub struct West;
+
+impl fmt::Debug for dyn Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Compare a robot's current state with a concrete one, e.g. `robot.facing == East`
+macro_rules! compare_with_state {
+    ($($state:ident),*) => {
+        $(impl PartialEq<$state> for Arc<dyn Direction> {
+            fn eq(&self, other: &$state) -> bool {
+                self.name() == other.name()
+            }
+        })*
+    };
+}
+
+compare_with_state!(North, East, South, West);
 
 // Implementation for North state
 impl Direction for North {
@@ -25,7 +49,7 @@ impl Direction for North {
     }
 
     fn advance(&self, x: i32, y: i32) -> (i32, i32) {
-        (x, y + 1)
+        (x, y.saturating_add(1))
     }
 
     fn name(&self) -> &'static str {
@@ -44,7 +68,7 @@ impl Direction for East {
     }
 
     fn advance(&self, x: i32, y: i32) -> (i32, i32) {
-        (x + 1, y)
+        (x.saturating_add(1), y)
     }
 
     fn name(&self) -> &'static str {
@@ -63,7 +87,7 @@ impl Direction for South {
     }
 
     fn advance(&self, x: i32, y: i32) -> (i32, i32) {
-        (x, y - 1)
+        (x, y.saturating_sub(1))
     }
 
     fn name(&self) -> &'static str {
@@ -82,7 +106,7 @@ impl Direction for West {
     }
 
     fn advance(&self, x: i32, y: i32) -> (i32, i32) {
-        (x - 1, y)
+        (x.saturating_sub(1), y)
     }
 
     fn name(&self) -> &'static str {
@@ -106,7 +130,7 @@ impl Robot {
         }
     }
 
-    fn execute(&mut self, movement: char) {
+    pub fn execute(&mut self, movement: char) {
         match movement {
             'L' => self.turn_left(),
             'R' => self.turn_right(),
@@ -131,7 +155,7 @@ impl Robot {
 }
 
 pub fn run() {
-    println!("Running State Pattern Solution!");
+    tracing::info!("Running State Pattern Solution!");
 }
 
 #[cfg(test)]
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Position {
     pub x: i32,
     pub y: i32,
@@ -10,16 +10,16 @@ pub struct Position {
 pub trait Face {}
 
 // Define types for each direction
-#[derive(Clone, ToSchema)]
+#[derive(Debug, PartialEq, Clone, ToSchema)]
 pub struct North;
 
-#[derive(Clone, ToSchema)]
+#[derive(Debug, PartialEq, Clone, ToSchema)]
 pub struct East;
 
-#[derive(Clone, ToSchema)]
+#[derive(Debug, PartialEq, Clone, ToSchema)]
 pub struct South;
 
-#[derive(Clone, ToSchema)]
+#[derive(Debug, PartialEq, Clone, ToSchema)]
 pub struct West;
 
 impl Face for North {}
@@ -34,7 +34,7 @@ pub type SouthRobot = Robot<South>;
 pub type WestRobot = Robot<West>;
 
 // Generic Robot struct
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Robot<S: Face> {
     pub position: Position,
 
@@ -75,7 +75,7 @@ impl Robot<North> {
     }
 
     pub fn advance(mut self) -> Self {
-        self.position.y += 1;
+        self.position.y = self.position.y.saturating_add(1);
         self
     }
 }
@@ -96,7 +96,7 @@ impl Robot<East> {
     }
 
     pub fn advance(mut self) -> Self {
-        self.position.x += 1;
+        self.position.x = self.position.x.saturating_add(1);
         self
     }
 }
@@ -117,7 +117,7 @@ impl Robot<South> {
     }
 
     pub fn advance(mut self) -> Self {
-        self.position.y -= 1;
+        self.position.y = self.position.y.saturating_sub(1);
         self
     }
 }
@@ -138,13 +138,13 @@ impl Robot<West> {
     }
 
     pub fn advance(mut self) -> Self {
-        self.position.x -= 1;
+        self.position.x = self.position.x.saturating_sub(1);
         self
     }
 }
 
 // Create an enum that can hold any of the typed robots
-#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, ToSchema)]
 pub enum RobotWithFace {
     North(NorthRobot),
     East(EastRobot),
@@ -163,6 +163,15 @@ impl RobotWithFace {
         }
     }
 
+    pub fn execute(&mut self, instruction: char) {
+        match instruction {
+            'L' => self.turn_left(),
+            'R' => self.turn_right(),
+            'A' => self.advance(),
+            _ => (),
+        }
+    }
+
     pub fn turn_left(&mut self) {
         match self {
             RobotWithFace::North(robot) => *self = RobotWithFace::West(robot.clone().turn_left()),
@@ -191,8 +200,14 @@ impl RobotWithFace {
     }
 }
 
+impl Default for RobotWithFace {
+    fn default() -> Self {
+        RobotWithFace::North(Robot::new(0, 0))
+    }
+}
+
 pub fn run() {
-    println!("Running Type State Pattern Solution!");
+    tracing::info!("Running Type State Pattern Solution!");
 }
 
 #[cfg(test)]
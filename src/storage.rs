@@ -0,0 +1,84 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Directory holding everything the server persists.
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Storage { dir: dir.into() }
+    }
+
+    /// `ROBOT_DATA_DIR`, defaulting to `data` in the working directory.
+    pub fn from_env() -> Self {
+        Storage::new(std::env::var_os("ROBOT_DATA_DIR").unwrap_or_else(|| "data".into()))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Create the directory if needed and prove a file can be written to it.
+    pub fn check_writable(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let probe = self.dir.join(".write-probe");
+        fs::write(&probe, b"ok")?;
+        fs::remove_file(probe)
+    }
+
+    /// Write `value` as JSON to `name`, replacing the previous file atomically.
+    pub fn write_json(&self, name: &str, value: &impl Serialize) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_vec_pretty(value)?;
+        let staging = self.dir.join(format!("{name}.tmp"));
+        fs::write(&staging, json)?;
+        fs::rename(staging, self.dir.join(name))
+    }
+
+    /// Read the JSON file `name`, or `None` when it was never written.
+    pub fn read_json<T: DeserializeOwned>(&self, name: &str) -> io::Result<Option<T>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A `Storage` in a fresh temporary directory that is removed on drop.
+#[cfg(test)]
+pub struct TempStorage(Storage);
+
+#[cfg(test)]
+impl TempStorage {
+    pub fn new(name: &str) -> Self {
+        TempStorage(Storage::new(
+            std::env::temp_dir().join(format!("robot-{name}-{}", uuid::Uuid::new_v4())),
+        ))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempStorage {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempStorage {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.0.dir());
+    }
+}
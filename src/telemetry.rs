@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id taken from a client.
+pub const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Whether a client's request id is short and plain enough to log and echo.
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+}
+
+/// Install the global subscriber.
+///
+/// `RUST_LOG` selects levels (default `info`), `ROBOT_LOG_FORMAT=json` switches to JSON lines.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match std::env::var("ROBOT_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().with_current_span(true).init(),
+        _ => builder.init(),
+    }
+}
+
+/// Run each request in a span carrying its request id, echoed in `X-Request-Id`.
+///
+/// A request id sent by the client is kept when it has at most 64 letters, digits, `-`, `_`,
+/// `.` or `:`; otherwise a new one is generated.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+    );
+    let started = Instant::now();
+
+    let mut response = next.call(req).instrument(span.clone()).await?;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_secs_f64() * 1000.0,
+            "request finished"
+        )
+    });
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    Ok(response.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use crate::telemetry::{request_id, MAX_REQUEST_ID_LEN, REQUEST_ID};
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(request_id))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let returned = |resp: &actix_web::dev::ServiceResponse| {
+            resp.headers()
+                .get(REQUEST_ID)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        let generated = returned(&resp);
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID, "client-42.a:b_c"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(returned(&resp), "client-42.a:b_c");
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for sent in [too_long.as_str(), "with space", "quote\"", ""] {
+            let req = TestRequest::get()
+                .uri("/")
+                .insert_header((REQUEST_ID, sent))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            let id = returned(&resp);
+            assert_ne!(id, sent);
+            assert!(uuid::Uuid::parse_str(&id).is_ok(), "{sent}");
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{RwLock, RwLockReadGuard};
+use std::{fmt, io};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{authorize, Permission};
+use crate::controller::{ErrorResponse, DEFAULT_ROBOT};
+use crate::pose::{Heading, Pose};
+use crate::storage::Storage;
+
+/// File in the data directory holding the world map.
+pub const WORLD_FILE: &str = "world.json";
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, ToSchema,
+)]
+pub struct Cell {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Cell {
+    /// The cell `distance` steps away towards `heading`.
+    pub fn towards(self, heading: Heading, distance: i32) -> Cell {
+        let (dx, dy) = heading.offset();
+        Cell {
+            x: self.x.saturating_add(dx * distance),
+            y: self.y.saturating_add(dy * distance),
+        }
+    }
+}
+
+impl From<Pose> for Cell {
+    fn from(pose: Pose) -> Self {
+        Cell {
+            x: pose.x,
+            y: pose.y,
+        }
+    }
+}
+
+/// The arena, inclusive on all sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Bounds {
+    pub min_x: i32,
+    pub min_y: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+}
+
+impl Bounds {
+    pub fn contains(&self, cell: Cell) -> bool {
+        (self.min_x..=self.max_x).contains(&cell.x) && (self.min_y..=self.max_y).contains(&cell.y)
+    }
+}
+
+/// What occupies a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Reading {
+    Free,
+    Obstacle,
+    Robot,
+    /// Outside the arena.
+    Edge,
+}
+
+impl Reading {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reading::Free => "free",
+            Reading::Obstacle => "obstacle",
+            Reading::Robot => "robot",
+            Reading::Edge => "edge",
+        }
+    }
+}
+
+/// Fixed features of the grid the robots drive on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct WorldMap {
+    /// The arena; unbounded when absent.
+    #[serde(default)]
+    pub bounds: Option<Bounds>,
+    #[serde(default)]
+    pub obstacles: BTreeSet<Cell>,
+    /// Cells that recharge a robot's battery while it stands on them.
+    #[serde(default)]
+    pub charging_stations: BTreeSet<Cell>,
+}
+
+impl WorldMap {
+    /// What a robot moving into `cell` would run into, given the cells other robots stand on.
+    pub fn probe(&self, cell: Cell, robots: &HashSet<Cell>) -> Reading {
+        if self.bounds.is_some_and(|bounds| !bounds.contains(cell)) {
+            Reading::Edge
+        } else if self.obstacles.contains(&cell) {
+            Reading::Obstacle
+        } else if robots.contains(&cell) {
+            Reading::Robot
+        } else {
+            Reading::Free
+        }
+    }
+
+    /// Cells from `from` to the nearest blocked cell towards `heading`, `1` being the next one.
+    ///
+    /// `None` when nothing is in the way of an unbounded arena.
+    pub fn distance_ahead(
+        &self,
+        from: Cell,
+        heading: Heading,
+        robots: &HashSet<Cell>,
+    ) -> Option<u32> {
+        let (dx, dy) = (i64::from(heading.offset().0), i64::from(heading.offset().1));
+        // Steps to reach the cell at `(x, y)` when it lies straight ahead. Bounds may sit at
+        // the ends of `i32`, so this counts in `i64`.
+        let steps = |x: i64, y: i64| {
+            let (ahead_x, ahead_y) = (x - i64::from(from.x), y - i64::from(from.y));
+            let ahead = ahead_x * dx + ahead_y * dy;
+            (ahead > 0 && ahead_x * dy == 0 && ahead_y * dx == 0)
+                .then(|| u32::try_from(ahead).unwrap_or(u32::MAX))
+        };
+        let edge = self.bounds.and_then(|bounds| {
+            let (from_x, from_y) = (i64::from(from.x), i64::from(from.y));
+            let (x, y) = match heading {
+                Heading::North => (from_x, i64::from(bounds.max_y) + 1),
+                Heading::East => (i64::from(bounds.max_x) + 1, from_y),
+                Heading::South => (from_x, i64::from(bounds.min_y) - 1),
+                Heading::West => (i64::from(bounds.min_x) - 1, from_y),
+            };
+            steps(x, y)
+        });
+        self.obstacles
+            .iter()
+            .chain(robots)
+            .filter_map(|cell| steps(i64::from(cell.x), i64::from(cell.y)))
+            .chain(edge)
+            .min()
+    }
+
+    /// Where a robot running `program` from `start` would first drive into an obstacle or
+    /// off the arena, if anywhere. Other robots do not block it.
+    ///
+    /// The ends of `i32` are an edge even without bounds, so no move wraps around.
+    pub fn collision(&self, start: Pose, program: &[char]) -> Option<Blocked> {
+        let mut pose = start;
+        for (steps, &instruction) in program.iter().enumerate() {
+            if instruction == 'A' {
+                let here = Cell::from(pose);
+                let cell = here.towards(pose.facing, 1);
+                let reading = if cell == here {
+                    Reading::Edge
+                } else {
+                    self.probe(cell, &HashSet::new())
+                };
+                if reading != Reading::Free {
+                    return Some(Blocked {
+                        steps,
+                        cell,
+                        reading,
+                    });
+                }
+            }
+            pose = pose.step(instruction);
+        }
+        None
+    }
+}
+
+/// The first move of a program that would run into the world map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blocked {
+    /// Instructions that can run before it.
+    pub steps: usize,
+    /// The cell the robot would have driven into.
+    pub cell: Cell,
+    pub reading: Reading,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "collision with {} at {},{} after {} instructions",
+            self.reading.name(),
+            self.cell.x,
+            self.cell.y,
+            self.steps
+        )
+    }
+}
+
+/// The world map, written through to the data directory when it changes.
+pub struct World {
+    storage: Storage,
+    map: RwLock<WorldMap>,
+}
+
+impl World {
+    pub fn load(storage: Storage) -> io::Result<Self> {
+        let map = storage.read_json(WORLD_FILE)?.unwrap_or_default();
+        Ok(World {
+            storage,
+            map: RwLock::new(map),
+        })
+    }
+
+    pub fn map(&self) -> RwLockReadGuard<'_, WorldMap> {
+        self.map.read().unwrap()
+    }
+
+    fn replace(&self, map: WorldMap) -> io::Result<()> {
+        let mut current = self.map.write().unwrap();
+        self.storage.write_json(WORLD_FILE, &map)?;
+        *current = map;
+        Ok(())
+    }
+}
+
+/// The world map.
+#[utoipa::path(
+    get,
+    path = "/world",
+    responses(
+        (status = 200, description = "Current world map", body = WorldMap)
+    )
+)]
+pub async fn get_world(world: web::Data<World>, http: HttpRequest) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::Read, DEFAULT_ROBOT) {
+        return response;
+    }
+    HttpResponse::Ok().json(&*world.map())
+}
+
+/// Replace the world map.
+#[utoipa::path(
+    put,
+    path = "/world",
+    request_body = WorldMap,
+    responses(
+        (status = 200, description = "World map replaced", body = WorldMap),
+        (status = 400, description = "Bounds are empty", body = ErrorResponse)
+    )
+)]
+pub async fn put_world(
+    world: web::Data<World>,
+    http: HttpRequest,
+    req: web::Json<WorldMap>,
+) -> impl Responder {
+    if let Some(response) = authorize(&http, Permission::EditWorld, DEFAULT_ROBOT) {
+        return response;
+    }
+    let map = req.into_inner();
+    if map
+        .bounds
+        .is_some_and(|bounds| bounds.min_x > bounds.max_x || bounds.min_y > bounds.max_y)
+    {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "bounds must have min_x <= max_x and min_y <= max_y",
+        ));
+    }
+    if let Err(err) = world.replace(map.clone()) {
+        tracing::error!(%err, "world write failed");
+        return HttpResponse::InternalServerError().json(ErrorResponse::new(format!(
+            "could not save the world: {err}"
+        )));
+    }
+    tracing::info!(
+        obstacles = map.obstacles.len(),
+        charging_stations = map.charging_stations.len(),
+        "world replaced"
+    );
+    HttpResponse::Ok().json(map)
+}